@@ -0,0 +1,150 @@
+use std::net::IpAddr;
+
+// a network in CIDR notation, e.g. 10.0.0.0/8 or 2001:db8::/32
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    // a plain address is a network of just that address
+    pub fn parse(cidr: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid network {:?}", cidr);
+        let (address, prefix_len) = match cidr.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (cidr, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = prefix_len as usize / 8;
+    let rest_bits = prefix_len % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+// who may use the proxy and where requests may go
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Acl {
+    // clients allowed to connect, everyone if empty
+    pub allow_clients: Vec<Cidr>,
+    // clients turned away even if allow_clients lets them in
+    pub deny_clients: Vec<Cidr>,
+    // destination hosts requests are refused for, see host_matches
+    pub deny_hosts: Vec<String>,
+}
+
+impl Acl {
+    pub fn allows_client(&self, ip: IpAddr) -> bool {
+        (self.allow_clients.is_empty() || self.allow_clients.iter().any(|cidr| cidr.contains(ip)))
+            && !self.deny_clients.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn allows_host(&self, host: &str) -> bool {
+        !self
+            .deny_hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, host))
+    }
+}
+
+// "*.example.com" matches every subdomain of example.com, anything else only the
+// exact host. case-insensitive, a trailing dot on the host is ignored
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    let host = host.trim_end_matches('.');
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .len()
+            .checked_sub(domain.len() + 1)
+            .map(|dot| host.as_bytes()[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(domain))
+            .unwrap_or(false),
+        None => host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+#[test]
+fn test_cidr_contains() {
+    let tests = vec![
+        ("10.0.0.0/8", "10.1.2.3", true),
+        ("10.0.0.0/8", "11.0.0.1", false),
+        ("192.168.1.128/25", "192.168.1.200", true),
+        ("192.168.1.128/25", "192.168.1.100", false),
+        ("0.0.0.0/0", "203.0.113.9", true),
+        ("127.0.0.1", "127.0.0.1", true),
+        ("127.0.0.1", "127.0.0.2", false),
+        ("10.0.0.0/8", "::ffff:10.0.0.1", true),
+        ("2001:db8::/32", "2001:db8:1::1", true),
+        ("2001:db8::/32", "2001:db9::1", false),
+        ("2001:db8::/32", "10.0.0.1", false),
+    ];
+
+    for (cidr, ip, expected) in tests {
+        let actual = Cidr::parse(cidr).unwrap().contains(ip.parse().unwrap());
+        assert_eq!(actual, expected, "{} {}", cidr, ip);
+    }
+
+    for invalid in ["10.0.0.0/33", "10.0.0/8", "example.com", "::/129"] {
+        assert!(Cidr::parse(invalid).is_err(), "{}", invalid);
+    }
+}
+
+#[test]
+fn test_acl() {
+    let acl = Acl {
+        allow_clients: vec![Cidr::parse("10.0.0.0/8").unwrap()],
+        deny_clients: vec![Cidr::parse("10.0.0.13").unwrap()],
+        deny_hosts: vec!["*.internal".to_string(), "metadata.example.com".to_string()],
+    };
+
+    assert!(acl.allows_client("10.0.0.1".parse().unwrap()));
+    assert!(!acl.allows_client("10.0.0.13".parse().unwrap()));
+    assert!(!acl.allows_client("192.0.2.1".parse().unwrap()));
+
+    assert!(acl.allows_host("example.com"));
+    assert!(acl.allows_host("internal"));
+    assert!(acl.allows_host("notinternal"));
+    assert!(!acl.allows_host("db.internal"));
+    assert!(!acl.allows_host("a.b.Internal."));
+    assert!(!acl.allows_host("Metadata.example.com"));
+    assert!(acl.allows_host("www.metadata.example.com"));
+
+    assert!(Acl::default().allows_client("192.0.2.1".parse().unwrap()));
+}
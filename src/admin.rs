@@ -0,0 +1,387 @@
+use crate::{
+    balancer::Endpoint, config::Config, http_method::Method, http_request::HttpRequest,
+    http_response::HttpResponse, server::text_response, status_code::StatusCode,
+};
+
+// answers a request to the admin listener. nothing on it is ever proxied
+pub fn admin_response(request: &HttpRequest, config: &Config, draining: bool) -> HttpResponse {
+    if request.method != Method::Get {
+        let mut response = text_response(StatusCode::MethodNotAllowed, "Method Not Allowed\n");
+        response.headers.insert("Allow", "GET");
+        return response;
+    }
+    match request.url.path() {
+        // the process is up and serving, load balancers should not restart it
+        "/healthz" => text_response(StatusCode::OK, "OK"),
+        // the proxy takes new requests, fails once shutdown started
+        "/readyz" => readiness_response(draining),
+        "/version" => text_response(
+            StatusCode::OK,
+            &format!("{} {}\n", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        ),
+        "/config" => text_response(StatusCode::OK, &redacted_config(config)),
+        "/clusters" => text_response(StatusCode::OK, &clusters(config)),
+        "/metrics" => {
+            let mut response = text_response(StatusCode::OK, &metrics(config));
+            response
+                .headers
+                .insert("Content-Type", "text/plain; version=0.0.4");
+            response
+        }
+        _ => text_response(StatusCode::NotFound, "Not Found\n"),
+    }
+}
+
+// one "key = value" line per setting, named like in the config file. default header
+// values may be credentials and the acl tells what is reachable behind the proxy, those
+// are only shown as header names and counts. endpoints are listed on /clusters
+fn redacted_config(config: &Config) -> String {
+    let default_headers: Vec<&str> = config
+        .default_headers
+        .iter()
+        .map(|header| header.name.as_str())
+        .collect();
+    let clusters: Vec<&str> = config
+        .routing
+        .iter()
+        .flat_map(|routing| routing.clusters())
+        .map(|cluster| cluster.name.as_str())
+        .collect();
+    let settings = vec![
+        ("listener.address", config.address.clone()),
+        ("listener.port", config.port.clone()),
+        ("listener.workers", config.workers.to_string()),
+        (
+            "listener.max_connections",
+            config.max_connections.to_string(),
+        ),
+        ("listener.accept_backlog", config.accept_backlog.to_string()),
+        (
+            "listener.connect_ports",
+            format!("{:?}", config.connect_ports),
+        ),
+        (
+            "listener.shutdown_timeout",
+            format!("{:?}", config.shutdown_timeout),
+        ),
+        (
+            "listener.keep_alive_timeout",
+            format!("{:?}", config.keep_alive_timeout),
+        ),
+        ("admin.address", config.admin_address.clone()),
+        ("admin.port", format!("{:?}", config.admin_port)),
+        ("upstream.pool.max_idle", config.pool.max_idle.to_string()),
+        (
+            "upstream.pool.max_idle_per_host",
+            config.pool.max_idle_per_host.to_string(),
+        ),
+        (
+            "upstream.pool.idle_timeout",
+            format!("{:?}", config.pool.idle_timeout),
+        ),
+        (
+            "upstream.timeouts.connect",
+            format!("{:?}", config.timeouts.connect),
+        ),
+        (
+            "upstream.timeouts.first_byte",
+            format!("{:?}", config.timeouts.first_byte),
+        ),
+        (
+            "upstream.timeouts.idle_read",
+            format!("{:?}", config.timeouts.idle_read),
+        ),
+        (
+            "upstream.timeouts.total",
+            format!("{:?}", config.timeouts.total),
+        ),
+        (
+            "upstream.retry.max_attempts",
+            config.retry.max_attempts.to_string(),
+        ),
+        (
+            "upstream.retry.backoff",
+            format!("{:?}", config.retry.base_backoff),
+        ),
+        (
+            "upstream.retry.max_backoff",
+            format!("{:?}", config.retry.max_backoff),
+        ),
+        (
+            "upstream.retry.statuses",
+            format!(
+                "{:?}",
+                config
+                    .retry
+                    .retry_statuses
+                    .iter()
+                    .map(|status| status.to_u32())
+                    .collect::<Vec<_>>()
+            ),
+        ),
+        (
+            "upstream.retry.budget_percent",
+            config.retry.budget_percent.to_string(),
+        ),
+        (
+            "upstream.retry.budget_min_retries",
+            config.retry.budget_min_retries.to_string(),
+        ),
+        (
+            "upstream.forwarding.via",
+            format!("{:?}", config.forwarding.via),
+        ),
+        (
+            "upstream.forwarding.x_forwarded_for",
+            config.forwarding.x_forwarded_for.to_string(),
+        ),
+        (
+            "upstream.forwarding.x_forwarded_proto",
+            config.forwarding.x_forwarded_proto.to_string(),
+        ),
+        (
+            "upstream.forwarding.forwarded",
+            config.forwarding.forwarded.to_string(),
+        ),
+        ("default_headers", format!("{:?}", default_headers)),
+        (
+            "acl.allow_clients",
+            format!("<{} redacted>", config.acl.allow_clients.len()),
+        ),
+        (
+            "acl.deny_clients",
+            format!("<{} redacted>", config.acl.deny_clients.len()),
+        ),
+        (
+            "acl.deny_hosts",
+            format!("<{} redacted>", config.acl.deny_hosts.len()),
+        ),
+        ("clusters", format!("{:?}", clusters)),
+        ("logging.level", format!("{:?}", config.log_level)),
+    ];
+    settings
+        .into_iter()
+        .map(|(key, value)| format!("{} = {}\n", key, value))
+        .collect()
+}
+
+// one line per endpoint of every cluster, e.g.
+// "users 10.0.0.1:8080 weight=1 in_flight=3 healthy=true". empty as a forward proxy
+fn clusters(config: &Config) -> String {
+    let mut body = String::new();
+    let clusters = config.routing.iter().flat_map(|routing| routing.clusters());
+    for cluster in clusters {
+        for endpoint in cluster.balancer.endpoints() {
+            body.push_str(&format!(
+                "{} {} weight={} in_flight={} healthy={}\n",
+                cluster.name,
+                endpoint.address,
+                endpoint.weight,
+                endpoint.in_flight(),
+                endpoint.is_healthy()
+            ));
+        }
+    }
+    body
+}
+
+// name, type, help and the value of one endpoint
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&Endpoint) -> u64,
+);
+
+// the state of every endpoint in the prometheus text format
+fn metrics(config: &Config) -> String {
+    let families: [Metric; 3] = [
+        (
+            "proxyrs_endpoint_healthy",
+            "gauge",
+            "Whether the endpoint is in rotation.",
+            |endpoint| endpoint.is_healthy() as u64,
+        ),
+        (
+            "proxyrs_endpoint_health_changes_total",
+            "counter",
+            "Times the endpoint went in or out of rotation.",
+            Endpoint::health_changes,
+        ),
+        (
+            "proxyrs_endpoint_in_flight",
+            "gauge",
+            "Requests the endpoint is serving.",
+            |endpoint| endpoint.in_flight() as u64,
+        ),
+    ];
+    let mut body = String::new();
+    for (name, kind, help, value) in families {
+        body.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        let clusters = config.routing.iter().flat_map(|routing| routing.clusters());
+        for cluster in clusters {
+            for endpoint in cluster.balancer.endpoints() {
+                body.push_str(&format!(
+                    "{}{{cluster=\"{}\",endpoint=\"{}\"}} {}\n",
+                    name,
+                    label_value(&cluster.name),
+                    label_value(&endpoint.address),
+                    value(endpoint)
+                ));
+            }
+        }
+    }
+    body
+}
+
+fn label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn readiness_response(draining: bool) -> HttpResponse {
+    if draining {
+        text_response(StatusCode::ServiceUnavailable, "Draining")
+    } else {
+        text_response(StatusCode::OK, "OK")
+    }
+}
+
+#[test]
+fn test_readiness_response_draining() {
+    assert_eq!(readiness_response(false).status_code, StatusCode::OK);
+    assert_eq!(
+        readiness_response(true).status_code,
+        StatusCode::ServiceUnavailable
+    );
+}
+
+#[test]
+fn test_admin_response() {
+    let tests = vec![
+        ("GET", "/healthz", false, 200, "OK"),
+        ("GET", "/healthz", true, 200, "OK"),
+        ("GET", "/readyz", false, 200, "OK"),
+        ("GET", "/readyz", true, 503, "Draining"),
+        ("GET", "/version", false, 200, "proxyrs "),
+        (
+            "GET",
+            "/config",
+            false,
+            200,
+            "listener.max_connections = 10000",
+        ),
+        ("GET", "/clusters", false, 200, ""),
+        (
+            "GET",
+            "/metrics",
+            false,
+            200,
+            "# TYPE proxyrs_endpoint_healthy gauge",
+        ),
+        ("GET", "/healthcare", false, 404, "Not Found"),
+        ("POST", "/healthz", false, 405, "Method Not Allowed"),
+    ];
+
+    for (method, path, draining, expected_status, expected_body) in tests {
+        let raw_request = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n\r\n", method, path);
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let response = admin_response(&request, &Config::default(), draining);
+        assert_eq!(response.status_code.to_u32(), expected_status, "{}", path);
+        assert!(
+            response.body_text().unwrap().contains(expected_body),
+            "{}",
+            path
+        );
+    }
+}
+
+#[test]
+fn test_clusters() {
+    use crate::{
+        balancer::Policy,
+        routing::{Cluster, PathMatch, Route, RoutingTable},
+    };
+
+    let cluster = Cluster::new(
+        "users",
+        Policy::LeastConnections,
+        vec![
+            Endpoint::new("10.0.0.1:8080", 1),
+            Endpoint::new("10.0.0.2:8080", 2),
+        ],
+    )
+    .unwrap();
+    let raw_request = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+    let _in_flight = cluster.balancer.pick(&request).unwrap();
+    let config = Config {
+        routing: Some(
+            RoutingTable::new(
+                vec![Route {
+                    host: None,
+                    path: PathMatch::Any,
+                    methods: vec![],
+                    cluster: "users".to_string(),
+                    rewrite: None,
+                }],
+                vec![cluster],
+            )
+            .unwrap(),
+        ),
+        ..Config::default()
+    };
+
+    let endpoints = config.routing.as_ref().unwrap().clusters()[0]
+        .balancer
+        .endpoints();
+    endpoints[1].set_healthy(false);
+
+    assert_eq!(
+        clusters(&config),
+        "users 10.0.0.1:8080 weight=1 in_flight=1 healthy=true\n\
+         users 10.0.0.2:8080 weight=2 in_flight=0 healthy=false\n"
+    );
+    let metrics = metrics(&config);
+    for expected in [
+        "proxyrs_endpoint_healthy{cluster=\"users\",endpoint=\"10.0.0.1:8080\"} 1\n",
+        "proxyrs_endpoint_healthy{cluster=\"users\",endpoint=\"10.0.0.2:8080\"} 0\n",
+        "proxyrs_endpoint_health_changes_total{cluster=\"users\",endpoint=\"10.0.0.2:8080\"} 1\n",
+        "proxyrs_endpoint_in_flight{cluster=\"users\",endpoint=\"10.0.0.1:8080\"} 1\n",
+    ] {
+        assert!(metrics.contains(expected), "{}", expected);
+    }
+    assert_eq!(label_value("a\"b\\c"), "a\\\"b\\\\c");
+}
+
+#[test]
+fn test_config_is_redacted() {
+    use crate::{acl::Cidr, default_headers::MergeMode};
+
+    let mut config = Config::default();
+    config
+        .default_headers
+        .add("Authorization", "Bearer secret-token", MergeMode::Override)
+        .unwrap();
+    config.acl.deny_clients = vec![Cidr::parse("192.0.2.0/24").unwrap()];
+    config.acl.deny_hosts = vec!["db.internal".to_string()];
+
+    let raw_request = "GET /config HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+    let response = admin_response(&request, &config, false);
+    let body = response.body_text().unwrap();
+    assert!(
+        body.contains("default_headers = [\"Authorization\"]"),
+        "{}",
+        body
+    );
+    assert!(body.contains("acl.deny_clients = <1 redacted>"), "{}", body);
+    for secret in ["secret-token", "192.0.2.0", "db.internal"] {
+        assert!(!body.contains(secret), "{}", body);
+    }
+}
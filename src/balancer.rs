@@ -0,0 +1,523 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{http_request::HttpRequest, utils::parse_authority, utils::random_u64};
+
+// points every unit of weight puts on the consistent hashing ring. more points
+// spread keys more evenly
+const RING_POINTS_PER_WEIGHT: usize = 100;
+
+// highest endpoint weight, keeps the ring of a consistent hashing cluster small
+pub const MAX_WEIGHT: u32 = 10_000;
+
+// how a cluster picks the endpoint for a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Policy {
+    // endpoints take turns
+    RoundRobin,
+    // endpoints take turns in proportion to their weight, spread out evenly
+    Weighted,
+    // the endpoint with the fewest requests in flight relative to its weight
+    LeastConnections,
+    // the less busy of two endpoints picked at random
+    RandomTwoChoices,
+    // requests with the same key go to the same endpoint as long as the cluster
+    // doesn't change. requests without the key take turns
+    ConsistentHash(HashKey),
+}
+
+// what a request is hashed on for Policy::ConsistentHash
+#[derive(Debug, Clone, PartialEq)]
+pub enum HashKey {
+    Header(String),
+    Cookie(String),
+}
+
+impl HashKey {
+    fn value<'a>(&self, request: &'a HttpRequest) -> Option<&'a str> {
+        match self {
+            HashKey::Header(name) => request.headers.get(name),
+            HashKey::Cookie(name) => request
+                .headers
+                .get_all("Cookie")
+                .flat_map(|cookies| cookies.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie, _)| cookie == name)
+                .map(|(_, value)| value),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Endpoint {
+    // host:port
+    pub address: String,
+    pub weight: u32,
+    in_flight: Arc<AtomicUsize>,
+    // unhealthy endpoints get no requests, every endpoint starts out healthy
+    healthy: AtomicBool,
+    // times healthy flipped
+    health_changes: AtomicU64,
+}
+
+impl Endpoint {
+    pub fn new(address: impl Into<String>, weight: u32) -> Self {
+        Self {
+            address: address.into(),
+            weight,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            healthy: AtomicBool::new(true),
+            health_changes: AtomicU64::new(0),
+        }
+    }
+
+    // requests sent to the endpoint that haven't finished yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    // true if the endpoint was not already in that state
+    pub fn set_healthy(&self, healthy: bool) -> bool {
+        let changed = self.healthy.swap(healthy, Ordering::Relaxed) != healthy;
+        if changed {
+            self.health_changes.fetch_add(1, Ordering::Relaxed);
+        }
+        changed
+    }
+
+    pub fn health_changes(&self) -> u64 {
+        self.health_changes.load(Ordering::Relaxed)
+    }
+
+    // takes over the health and the count of changes of the same endpoint in a
+    // config that was replaced, copying is not a change
+    pub fn inherit_health(&self, previous: &Endpoint) {
+        self.healthy.store(previous.is_healthy(), Ordering::Relaxed);
+        self.health_changes
+            .store(previous.health_changes(), Ordering::Relaxed);
+    }
+}
+
+// an endpoint picked for a request. the request counts as in flight until this is dropped
+#[derive(Debug)]
+pub struct InFlight {
+    pub address: String,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// picks the endpoint of a cluster every request goes to
+#[derive(Debug)]
+pub struct Balancer {
+    policy: Policy,
+    endpoints: Vec<Endpoint>,
+    next: AtomicUsize,
+    // smooth weighted round robin state, one entry per endpoint
+    current_weights: Mutex<Vec<i64>>,
+    // consistent hashing ring, points with the index of their endpoint sorted by point
+    ring: Vec<(u64, usize)>,
+}
+
+impl Balancer {
+    pub fn new(policy: Policy, endpoints: Vec<Endpoint>) -> Result<Self, String> {
+        if endpoints.is_empty() {
+            return Err("no endpoints".to_string());
+        }
+        for endpoint in &endpoints {
+            parse_authority(&endpoint.address).map_err(|e| e.to_string())?;
+            if endpoint.weight == 0 {
+                return Err(format!("endpoint {} has weight 0", endpoint.address));
+            }
+            if endpoint.weight > MAX_WEIGHT {
+                return Err(format!(
+                    "endpoint {} has weight {}, more than {}",
+                    endpoint.address, endpoint.weight, MAX_WEIGHT
+                ));
+            }
+        }
+
+        let mut ring = Vec::new();
+        if let Policy::ConsistentHash(_) = policy {
+            let points = |endpoint: &Endpoint| {
+                (endpoint.weight as usize).checked_mul(RING_POINTS_PER_WEIGHT)
+            };
+            let total = endpoints
+                .iter()
+                .try_fold(0usize, |total, endpoint| {
+                    total.checked_add(points(endpoint)?)
+                })
+                .ok_or_else(|| "too many points on the hashing ring".to_string())?;
+            ring.reserve_exact(total);
+            for (i, endpoint) in endpoints.iter().enumerate() {
+                let points = points(endpoint).unwrap_or_default();
+                ring.extend((0..points).map(|point| {
+                    (
+                        hash(format!("{}#{}", endpoint.address, point).as_bytes()),
+                        i,
+                    )
+                }));
+            }
+            ring.sort_unstable();
+        }
+
+        Ok(Self {
+            policy,
+            current_weights: Mutex::new(vec![0; endpoints.len()]),
+            endpoints,
+            next: AtomicUsize::new(0),
+            ring,
+        })
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
+    }
+
+    pub fn endpoints(&self) -> &[Endpoint] {
+        &self.endpoints
+    }
+
+    // None if every endpoint is unhealthy
+    pub fn pick(&self, request: &HttpRequest) -> Option<InFlight> {
+        self.pick_excluding(request, &[])
+    }
+
+    // picks like pick among the endpoints whose address is not in excluded, e.g. to
+    // send a request somewhere else after it could not be connected
+    pub fn pick_excluding(&self, request: &HttpRequest, excluded: &[String]) -> Option<InFlight> {
+        let endpoint = &self.endpoints[self.pick_index(request, excluded)?];
+        endpoint.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(InFlight {
+            address: endpoint.address.clone(),
+            in_flight: Arc::clone(&endpoint.in_flight),
+        })
+    }
+
+    fn pick_index(&self, request: &HttpRequest, excluded: &[String]) -> Option<usize> {
+        let candidate = |i: usize| {
+            let endpoint = &self.endpoints[i];
+            endpoint.is_healthy() && !excluded.contains(&endpoint.address)
+        };
+        let healthy: Vec<usize> = (0..self.endpoints.len())
+            .filter(|i| candidate(*i))
+            .collect();
+        let count = healthy.len();
+        if count == 0 {
+            return None;
+        }
+        let picked = match &self.policy {
+            Policy::RoundRobin => healthy[self.next.fetch_add(1, Ordering::Relaxed) % count],
+            Policy::Weighted => self.pick_weighted(&healthy),
+            Policy::LeastConnections => {
+                // scanning from a moving start spreads ties across endpoints
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count)
+                    .map(|offset| healthy[(start + offset) % count])
+                    .reduce(|best, i| if self.less_busy(i, best) { i } else { best })
+                    .unwrap_or_default()
+            }
+            Policy::RandomTwoChoices if count == 1 => healthy[0],
+            Policy::RandomTwoChoices => {
+                let first = random_u64() as usize % count;
+                let mut second = random_u64() as usize % (count - 1);
+                if second >= first {
+                    second += 1;
+                }
+                let (first, second) = (healthy[first], healthy[second]);
+                if self.less_busy(second, first) {
+                    second
+                } else {
+                    first
+                }
+            }
+            Policy::ConsistentHash(key) => match key.value(request) {
+                // keys of an unhealthy or excluded endpoint move on to the next one on the ring
+                Some(value) => {
+                    let point = hash(value.as_bytes());
+                    let start = self.ring.partition_point(|(p, _)| *p < point);
+                    (0..self.ring.len())
+                        .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                        .find(|i| candidate(*i))
+                        .unwrap_or(healthy[0])
+                }
+                None => healthy[self.next.fetch_add(1, Ordering::Relaxed) % count],
+            },
+        };
+        Some(picked)
+    }
+
+    // whether endpoint a has fewer requests in flight than b relative to their weights
+    fn less_busy(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (&self.endpoints[a], &self.endpoints[b]);
+        (a.in_flight() as u64 * b.weight as u64) < (b.in_flight() as u64 * a.weight as u64)
+    }
+
+    // smooth weighted round robin as in nginx: every pick adds each endpoint's weight
+    // to its current weight, takes the highest and takes the total off the winner
+    fn pick_weighted(&self, healthy: &[usize]) -> usize {
+        let mut current_weights = self
+            .current_weights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut best = healthy[0];
+        for &i in healthy {
+            current_weights[i] += self.endpoints[i].weight as i64;
+            if current_weights[i] > current_weights[best] {
+                best = i;
+            }
+        }
+        let total: i64 = healthy
+            .iter()
+            .map(|&i| self.endpoints[i].weight as i64)
+            .sum();
+        current_weights[best] -= total;
+        best
+    }
+}
+
+// 64-bit FNV-1a followed by the MurmurHash3 finalizer. both algorithms are fixed,
+// unlike std's hashers, so proxies built with any Rust version on any platform agree
+// on the ring. the finalizer spreads keys that only differ in their last bytes, which
+// FNV-1a alone leaves close together on the ring
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+fn request(headers: &str) -> HttpRequest {
+    let raw_request = format!("GET / HTTP/1.1\r\nHost: example.com\r\n{}\r\n", headers);
+    HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap()
+}
+
+#[cfg(test)]
+fn balancer(policy: Policy, weights: &[u32]) -> Balancer {
+    let endpoints = weights
+        .iter()
+        .enumerate()
+        .map(|(i, weight)| Endpoint::new(format!("10.0.0.{}:80", i), *weight))
+        .collect();
+    Balancer::new(policy, endpoints).unwrap()
+}
+
+#[test]
+fn test_round_robin_and_weighted() {
+    let tests = vec![
+        (Policy::RoundRobin, vec![1, 1, 1], vec![0, 1, 2, 0, 1, 2]),
+        (Policy::RoundRobin, vec![5, 1], vec![0, 1, 0, 1]),
+        (Policy::Weighted, vec![1, 1], vec![0, 1, 0, 1]),
+        // interleaved instead of three in a row
+        (Policy::Weighted, vec![3, 1], vec![0, 0, 1, 0, 0, 0, 1, 0]),
+        (Policy::Weighted, vec![5, 1, 1], vec![0, 0, 1, 0, 2, 0, 0]),
+    ];
+
+    for (policy, weights, expected) in tests {
+        let balancer = balancer(policy.clone(), &weights);
+        let picked: Vec<usize> = (0..expected.len())
+            .map(|_| balancer.pick_index(&request(""), &[]).unwrap())
+            .collect();
+        assert_eq!(picked, expected, "{:?} {:?}", policy, weights);
+    }
+}
+
+#[test]
+fn test_in_flight() {
+    let balancer = balancer(Policy::LeastConnections, &[1, 1, 2]);
+    let first = balancer.pick(&request("")).unwrap();
+    let second = balancer.pick(&request("")).unwrap();
+    let third = balancer.pick(&request("")).unwrap();
+    let fourth = balancer.pick(&request("")).unwrap();
+    // the endpoint of weight 2 takes twice as many
+    let mut addresses = vec![
+        &first.address,
+        &second.address,
+        &third.address,
+        &fourth.address,
+    ];
+    addresses.sort();
+    assert_eq!(
+        addresses,
+        vec!["10.0.0.0:80", "10.0.0.1:80", "10.0.0.2:80", "10.0.0.2:80"]
+    );
+
+    drop(first);
+    let in_flight: usize = balancer.endpoints().iter().map(Endpoint::in_flight).sum();
+    assert_eq!(in_flight, 3);
+    drop((second, third, fourth));
+    assert!(balancer.endpoints().iter().all(|e| e.in_flight() == 0));
+}
+
+#[test]
+fn test_random_two_choices_avoids_busy_endpoint() {
+    let balancer = balancer(Policy::RandomTwoChoices, &[1, 1]);
+    let busy = balancer.pick(&request("")).unwrap();
+    for _ in 0..10 {
+        assert_ne!(balancer.pick(&request("")).unwrap().address, busy.address);
+    }
+}
+
+#[test]
+fn test_fnv1a() {
+    // reference values of the FNV-1a test suite
+    let tests = vec![
+        ("", 0xcbf2_9ce4_8422_2325),
+        ("a", 0xaf63_dc4c_8601_ec8c),
+        ("foobar", 0x8594_4171_f739_67e8),
+    ];
+    for (input, expected) in tests {
+        assert_eq!(fnv1a(input.as_bytes()), expected, "{:?}", input);
+    }
+}
+
+#[test]
+fn test_consistent_hash() {
+    let tests = vec![
+        (HashKey::Header("X-User".to_string()), "X-User: "),
+        (
+            HashKey::Cookie("session".to_string()),
+            "Cookie: theme=dark; session=",
+        ),
+    ];
+
+    for (key, header) in tests {
+        let balancer = balancer(Policy::ConsistentHash(key.clone()), &[1, 1, 1, 1]);
+        let mut seen = std::collections::HashSet::new();
+        for user in 0..50 {
+            let request = request(&format!("{}user{}\r\n", header, user));
+            let picked = balancer.pick_index(&request, &[]).unwrap();
+            assert_eq!(
+                balancer.pick_index(&request, &[]),
+                Some(picked),
+                "{:?}",
+                key
+            );
+            seen.insert(picked);
+        }
+        // keys spread over every endpoint
+        assert_eq!(seen.len(), 4, "{:?}", key);
+    }
+
+    // removing an endpoint only moves the keys that were on it
+    let before = balancer(
+        Policy::ConsistentHash(HashKey::Header("X-User".to_string())),
+        &[1, 1, 1],
+    );
+    let after = Balancer::new(
+        Policy::ConsistentHash(HashKey::Header("X-User".to_string())),
+        vec![
+            Endpoint::new("10.0.0.0:80", 1),
+            Endpoint::new("10.0.0.1:80", 1),
+        ],
+    )
+    .unwrap();
+    for user in 0..50 {
+        let request = request(&format!("X-User: user{}\r\n", user));
+        let picked = before.pick_index(&request, &[]).unwrap();
+        if picked != 2 {
+            assert_eq!(after.pick_index(&request, &[]), Some(picked));
+        }
+    }
+}
+
+#[test]
+fn test_unhealthy_endpoints_leave_rotation() {
+    let policies = vec![
+        Policy::RoundRobin,
+        Policy::Weighted,
+        Policy::LeastConnections,
+        Policy::RandomTwoChoices,
+        Policy::ConsistentHash(HashKey::Header("X-User".to_string())),
+    ];
+
+    for policy in policies {
+        let balancer = balancer(policy.clone(), &[1, 2, 1]);
+        assert!(balancer.endpoints()[1].set_healthy(false));
+        assert!(!balancer.endpoints()[1].set_healthy(false));
+        for user in 0..20 {
+            let request = request(&format!("X-User: user{}\r\n", user));
+            assert_ne!(balancer.pick_index(&request, &[]), Some(1), "{:?}", policy);
+        }
+
+        balancer.endpoints()[0].set_healthy(false);
+        balancer.endpoints()[2].set_healthy(false);
+        assert!(balancer.pick(&request("")).is_none(), "{:?}", policy);
+
+        balancer.endpoints()[1].set_healthy(true);
+        assert_eq!(
+            balancer.pick_index(&request(""), &[]),
+            Some(1),
+            "{:?}",
+            policy
+        );
+        assert_eq!(balancer.endpoints()[1].health_changes(), 2);
+    }
+}
+
+#[test]
+fn test_pick_excluding() {
+    let policies = vec![
+        Policy::RoundRobin,
+        Policy::Weighted,
+        Policy::LeastConnections,
+        Policy::RandomTwoChoices,
+        Policy::ConsistentHash(HashKey::Header("X-User".to_string())),
+    ];
+
+    for policy in policies {
+        let balancer = balancer(policy.clone(), &[1, 2, 1]);
+        let tried = vec!["10.0.0.0:80".to_string(), "10.0.0.2:80".to_string()];
+        for user in 0..20 {
+            let request = request(&format!("X-User: user{}\r\n", user));
+            assert_eq!(
+                balancer.pick_index(&request, &tried),
+                Some(1),
+                "{:?}",
+                policy
+            );
+        }
+
+        let tried = vec![
+            "10.0.0.0:80".to_string(),
+            "10.0.0.1:80".to_string(),
+            "10.0.0.2:80".to_string(),
+        ];
+        assert!(
+            balancer.pick_excluding(&request(""), &tried).is_none(),
+            "{:?}",
+            policy
+        );
+    }
+}
+
+#[test]
+fn test_balancer_errors() {
+    assert!(Balancer::new(Policy::RoundRobin, vec![]).is_err());
+    assert!(Balancer::new(
+        Policy::ConsistentHash(HashKey::Header("X-User".to_string())),
+        vec![Endpoint::new("10.0.0.1:80", 4_000_000_000)],
+    )
+    .is_err());
+    assert!(Balancer::new(Policy::RoundRobin, vec![Endpoint::new("users", 1)]).is_err());
+    assert!(Balancer::new(Policy::Weighted, vec![Endpoint::new("users:80", 0)]).is_err());
+}
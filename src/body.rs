@@ -0,0 +1,303 @@
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{
+    chunked::{read_chunk_size, read_trailers},
+    error::ProxyError,
+    header_map::HeaderMap,
+    utils::{read_line, BodyFraming},
+};
+
+// bodies are forwarded in pieces of at most this many bytes, so the memory a
+// connection needs does not grow with the size of the body
+pub const CHUNK_SIZE: usize = 16 * 1024;
+
+enum State {
+    // bytes left in a Content-Length body
+    Length(u64),
+    // chunk-size line of the next chunk comes next
+    ChunkSize,
+    // bytes left in the current chunk
+    Chunk(u64),
+    UntilClose,
+    Done,
+}
+
+// reads a message body piece by piece as it arrives, decoding chunked encoding on the way.
+// nothing is read from the underlying reader until the body is asked for
+pub struct BodyReader<R> {
+    reader: R,
+    state: State,
+    trailers: HeaderMap,
+}
+
+impl<R: AsyncBufRead + Unpin> BodyReader<R> {
+    pub fn new(reader: R, framing: BodyFraming) -> Self {
+        let state = match framing {
+            BodyFraming::Empty | BodyFraming::ContentLength(0) => State::Done,
+            BodyFraming::ContentLength(length) => State::Length(length as u64),
+            BodyFraming::Chunked => State::ChunkSize,
+            BodyFraming::CloseDelimited => State::UntilClose,
+        };
+        Self {
+            reader,
+            state,
+            trailers: HeaderMap::new(),
+        }
+    }
+
+    // read the next piece of the body into buffer, 0 once the body ended
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, ProxyError> {
+        loop {
+            match self.state {
+                State::Done => return Ok(0),
+                State::Length(remaining) => {
+                    let num_bytes = self.read_at_most(buffer, remaining).await?;
+                    self.state = match remaining - num_bytes as u64 {
+                        0 => State::Done,
+                        remaining => State::Length(remaining),
+                    };
+                    return Ok(num_bytes);
+                }
+                State::ChunkSize => match read_chunk_size(&mut self.reader).await? {
+                    0 => {
+                        self.trailers = read_trailers(&mut self.reader).await?;
+                        self.state = State::Done;
+                    }
+                    size => self.state = State::Chunk(size),
+                },
+                State::Chunk(remaining) => {
+                    let num_bytes = self.read_at_most(buffer, remaining).await?;
+                    self.state = match remaining - num_bytes as u64 {
+                        0 => {
+                            let mut line = String::new();
+                            read_line(&mut self.reader, &mut line).await?;
+                            if line != "\r\n" {
+                                return Err(ProxyError::Parse(
+                                    "chunk data is not followed by CRLF".to_string(),
+                                ));
+                            }
+                            State::ChunkSize
+                        }
+                        remaining => State::Chunk(remaining),
+                    };
+                    return Ok(num_bytes);
+                }
+                State::UntilClose => {
+                    let num_bytes = self.reader.read(buffer).await?;
+                    if num_bytes == 0 {
+                        self.state = State::Done;
+                    }
+                    return Ok(num_bytes);
+                }
+            }
+        }
+    }
+
+    async fn read_at_most(
+        &mut self,
+        buffer: &mut [u8],
+        remaining: u64,
+    ) -> Result<usize, ProxyError> {
+        let max = buffer.len().min(remaining.try_into().unwrap_or(usize::MAX));
+        let num_bytes = self.reader.read(&mut buffer[..max]).await?;
+        if num_bytes == 0 {
+            return Err(ProxyError::Parse(
+                "connection closed before the end of the body".to_string(),
+            ));
+        }
+        Ok(num_bytes)
+    }
+
+    // buffer the rest of the body, only for callers that need all of it at once
+    pub async fn read_to_end(&mut self) -> Result<Vec<u8>, ProxyError> {
+        let mut body = Vec::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let num_bytes = self.read(&mut buffer).await?;
+            if num_bytes == 0 {
+                return Ok(body);
+            }
+            body.extend_from_slice(&buffer[..num_bytes]);
+        }
+    }
+
+    // whether the whole body was read, the reader is then positioned at the next message
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    // trailer fields of a chunked body, available once it was read to the end
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+// copy the rest of body to writer in CHUNK_SIZE pieces. every piece is written before the
+// next one is read, so a slow receiver slows down the sender instead of filling memory.
+// with chunked every piece becomes a chunk and the trailers follow the last chunk
+pub async fn copy_body<R: AsyncBufRead + Unpin, W: AsyncWrite + Unpin>(
+    body: &mut BodyReader<R>,
+    writer: &mut W,
+    chunked: bool,
+) -> Result<u64, ProxyError> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let num_bytes = body.read(&mut buffer).await?;
+        if num_bytes == 0 {
+            break;
+        }
+        if chunked {
+            writer
+                .write_all(format!("{:x}\r\n", num_bytes).as_bytes())
+                .await?;
+            writer.write_all(&buffer[..num_bytes]).await?;
+            writer.write_all(b"\r\n").await?;
+        } else {
+            writer.write_all(&buffer[..num_bytes]).await?;
+        }
+        total += num_bytes as u64;
+    }
+    if chunked {
+        let mut last_chunk = "0\r\n".to_string();
+        for (key, value) in body.trailers().iter() {
+            last_chunk.push_str(&format!("{}: {}\r\n", key, value));
+        }
+        last_chunk.push_str("\r\n");
+        writer.write_all(last_chunk.as_bytes()).await?;
+    }
+    writer.flush().await?;
+    Ok(total)
+}
+
+#[tokio::test]
+async fn test_body_reader_reads_in_pieces() {
+    let input = b"hello world, the rest stays in the stream";
+    let mut body = BodyReader::new(&input[..], BodyFraming::ContentLength(11));
+
+    let mut buffer = [0; 4];
+    let mut pieces = Vec::new();
+    loop {
+        let num_bytes = body.read(&mut buffer).await.unwrap();
+        if num_bytes == 0 {
+            break;
+        }
+        pieces.push(String::from_utf8(buffer[..num_bytes].to_vec()).unwrap());
+    }
+    assert_eq!(pieces, vec!["hell", "o wo", "rld"]);
+    assert!(body.is_done());
+    assert_eq!(body.into_inner(), b", the rest stays in the stream");
+}
+
+#[tokio::test]
+async fn test_body_reader_errors() {
+    // testcase struct
+    struct TestCase {
+        _name: String,
+        input: String,
+        framing: BodyFraming,
+    }
+
+    let test_cases = [
+        TestCase {
+            _name: "content length longer than the stream".to_string(),
+            input: "short".to_string(),
+            framing: BodyFraming::ContentLength(10),
+        },
+        TestCase {
+            _name: "missing last chunk".to_string(),
+            input: "5\r\nhello\r\n".to_string(),
+            framing: BodyFraming::Chunked,
+        },
+        TestCase {
+            _name: "chunk cut short".to_string(),
+            input: "a\r\nhello".to_string(),
+            framing: BodyFraming::Chunked,
+        },
+    ];
+
+    for test_case in test_cases.iter() {
+        let mut body = BodyReader::new(test_case.input.as_bytes(), test_case.framing);
+        assert!(body.read_to_end().await.is_err(), "{}", test_case._name);
+    }
+}
+
+#[tokio::test]
+async fn test_copy_body() {
+    // testcase struct
+    struct TestCase {
+        _name: String,
+        input: String,
+        framing: BodyFraming,
+        chunked: bool,
+        expected: String,
+    }
+
+    let test_cases = [
+        TestCase {
+            _name: "content length as is".to_string(),
+            input: "hello world".to_string(),
+            framing: BodyFraming::ContentLength(11),
+            chunked: false,
+            expected: "hello world".to_string(),
+        },
+        TestCase {
+            _name: "chunked with trailers".to_string(),
+            input: "5\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n".to_string(),
+            framing: BodyFraming::Chunked,
+            chunked: true,
+            expected: "5\r\nhello\r\n6\r\n world\r\n0\r\nExpires: never\r\n\r\n".to_string(),
+        },
+        TestCase {
+            _name: "content length to chunked".to_string(),
+            input: "hello".to_string(),
+            framing: BodyFraming::ContentLength(5),
+            chunked: true,
+            expected: "5\r\nhello\r\n0\r\n\r\n".to_string(),
+        },
+        TestCase {
+            _name: "empty chunked".to_string(),
+            input: "".to_string(),
+            framing: BodyFraming::Empty,
+            chunked: true,
+            expected: "0\r\n\r\n".to_string(),
+        },
+    ];
+
+    for test_case in test_cases.iter() {
+        let mut body = BodyReader::new(test_case.input.as_bytes(), test_case.framing);
+        let mut output = Vec::new();
+        copy_body(&mut body, &mut output, test_case.chunked)
+            .await
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            test_case.expected,
+            "{}",
+            test_case._name
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_copy_body_backpressure() {
+    // the receiving side holds far less than the body, so copying only finishes
+    // because the receiver keeps draining it
+    let body: Vec<u8> = (0..8 * CHUNK_SIZE).map(|i| (i % 251) as u8).collect();
+    let (mut writer, mut receiver) = tokio::io::duplex(1024);
+
+    let input = body.clone();
+    let sender = tokio::spawn(async move {
+        let mut reader = BodyReader::new(input.as_slice(), BodyFraming::ContentLength(input.len()));
+        copy_body(&mut reader, &mut writer, false).await.unwrap()
+    });
+
+    let mut received = Vec::new();
+    receiver.read_to_end(&mut received).await.unwrap();
+    assert_eq!(sender.await.unwrap(), body.len() as u64);
+    assert_eq!(received, body);
+}
@@ -0,0 +1,207 @@
+use tokio::io::AsyncBufRead;
+
+use crate::{
+    body::BodyReader,
+    error::ProxyError,
+    header_map::HeaderMap,
+    utils::{read_headers, read_line, BodyFraming},
+};
+
+// largest chunk accepted, guards against absurd sizes from a broken peer
+const MAX_CHUNK_SIZE: u64 = 1 << 32;
+
+// decode a body sent with Transfer-Encoding: chunked (RFC 9112 section 7.1).
+// chunk extensions are ignored, trailer fields are returned next to the body
+pub async fn read_chunked<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<(Vec<u8>, HeaderMap), ProxyError> {
+    let mut body_reader = BodyReader::new(reader, BodyFraming::Chunked);
+    let body = body_reader.read_to_end().await?;
+    Ok((body, body_reader.trailers().clone()))
+}
+
+// chunk-size [ chunk-ext ] CRLF
+pub async fn read_chunk_size<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<u64, ProxyError> {
+    let mut line = String::new();
+    if read_line(reader, &mut line).await? == 0 {
+        return Err(ProxyError::Parse(
+            "connection closed before the last chunk".to_string(),
+        ));
+    }
+    // from_str_radix would also take a sign, which peers that only accept hex digits
+    // would read as the end of the body
+    let size = line.split(';').next().unwrap_or_default().trim();
+    let size = Some(size)
+        .filter(|size| !size.is_empty() && size.bytes().all(|b| b.is_ascii_hexdigit()))
+        .and_then(|size| u64::from_str_radix(size, 16).ok())
+        .ok_or_else(|| ProxyError::Parse(format!("invalid chunk size {:?}", size)))?;
+    if size > MAX_CHUNK_SIZE {
+        return Err(ProxyError::Parse(format!(
+            "chunk size {} is too large",
+            size
+        )));
+    }
+    Ok(size)
+}
+
+// trailer section after the last chunk, ends with an empty line. read like the
+// header section, with the same limits
+pub async fn read_trailers<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<HeaderMap, ProxyError> {
+    read_headers(reader).await
+}
+
+// encode a whole body as a single chunk followed by the last chunk
+pub fn encode_chunked(body: &[u8]) -> Vec<u8> {
+    if body.is_empty() {
+        return b"0\r\n\r\n".to_vec();
+    }
+    let mut encoded = format!("{:x}\r\n", body.len()).into_bytes();
+    encoded.extend_from_slice(body);
+    encoded.extend_from_slice(b"\r\n0\r\n\r\n");
+    encoded
+}
+
+#[tokio::test]
+async fn test_read_chunked() {
+    // testcase struct
+    struct TestCase {
+        _name: String,
+        input: String,
+        expected_body: String,
+        expected_trailers: Vec<(String, String)>,
+        expected_error: bool,
+    }
+
+    let test_cases = [
+        TestCase {
+            _name: "two chunks".to_string(),
+            input: "5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n".to_string(),
+            expected_body: "hello world".to_string(),
+            expected_trailers: vec![],
+            expected_error: false,
+        },
+        TestCase {
+            _name: "hex sizes and extensions".to_string(),
+            input: "A;name=value\r\n0123456789\r\n1a;a;b=\"c\"\r\nabcdefghijklmnopqrstuvwxyz\r\n0;last\r\n\r\n"
+                .to_string(),
+            expected_body: "0123456789abcdefghijklmnopqrstuvwxyz".to_string(),
+            expected_trailers: vec![],
+            expected_error: false,
+        },
+        TestCase {
+            _name: "trailers".to_string(),
+            input: "2\r\nok\r\n0\r\nExpires: never\r\nX-Checksum: abc\r\n\r\n".to_string(),
+            expected_body: "ok".to_string(),
+            expected_trailers: vec![
+                ("Expires".to_string(), "never".to_string()),
+                ("X-Checksum".to_string(), "abc".to_string()),
+            ],
+            expected_error: false,
+        },
+        TestCase {
+            _name: "invalid size".to_string(),
+            input: "zz\r\nhello\r\n0\r\n\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "signed size".to_string(),
+            input: "+5\r\nhello\r\n0\r\n\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "empty size".to_string(),
+            input: "\r\nhello\r\n0\r\n\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "missing last chunk".to_string(),
+            input: "5\r\nhello\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "trailers cut off".to_string(),
+            input: "2\r\nok\r\n0\r\nExpires: never\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "last chunk without trailer section".to_string(),
+            input: "2\r\nok\r\n0\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "too many trailers".to_string(),
+            input: format!(
+                "2\r\nok\r\n0\r\n{}\r\n",
+                "X-Trailer: value\r\n".repeat(crate::utils::MAX_HEADERS + 1)
+            ),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "trailer line too long".to_string(),
+            input: format!(
+                "2\r\nok\r\n0\r\nX-Trailer: {}\r\n\r\n",
+                "a".repeat(crate::utils::MAX_LINE_LENGTH)
+            ),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+        TestCase {
+            _name: "chunk longer than its size".to_string(),
+            input: "2\r\nhello\r\n0\r\n\r\n".to_string(),
+            expected_body: "".to_string(),
+            expected_trailers: vec![],
+            expected_error: true,
+        },
+    ];
+
+    for test_case in test_cases.iter() {
+        let mut stream = test_case.input.as_bytes();
+        let (body, trailers) = match read_chunked(&mut stream).await {
+            Ok(decoded) => decoded,
+            Err(_e) => {
+                assert!(test_case.expected_error, "{}", test_case._name);
+                continue;
+            }
+        };
+        assert!(!test_case.expected_error, "{}", test_case._name);
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            test_case.expected_body,
+            "{}",
+            test_case._name
+        );
+        assert_eq!(
+            trailers,
+            test_case.expected_trailers.iter().cloned().collect(),
+            "{}",
+            test_case._name
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_encode_chunked_round_trip() {
+    for body in ["", "hello", "a longer body\r\nwith a line break"] {
+        let encoded = encode_chunked(body.as_bytes());
+        let (decoded, trailers) = read_chunked(&mut encoded.as_slice()).await.unwrap();
+        assert_eq!(String::from_utf8(decoded).unwrap(), body);
+        assert!(trailers.is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+use std::time::Duration;
+
+use crate::{
+    acl::Acl, connection_pool::PoolConfig, default_headers::DefaultHeaders,
+    forwarding::ForwardingConfig, retry::RetryPolicy, routing::RoutingTable, timeout::Timeouts,
+};
+
+// runtime configuration for the proxy, read from the environment
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: String,
+    pub port: String,
+    // where /healthz, /readyz, /version and /config are served, None for nowhere
+    pub admin_address: String,
+    pub admin_port: Option<String>,
+    // number of runtime worker threads handling connections
+    pub workers: usize,
+    // connections served concurrently before new ones are rejected with 503
+    pub max_connections: usize,
+    // connections the OS queues for the listener until they are accepted
+    pub accept_backlog: usize,
+    // destination ports CONNECT tunnels may be opened to
+    pub connect_ports: Vec<u16>,
+    // time in-flight connections get to finish once shutdown starts
+    pub shutdown_timeout: Duration,
+    // time an idle keep-alive connection is held open waiting for the next request
+    pub keep_alive_timeout: Duration,
+    // idle upstream connections kept for reuse
+    pub pool: PoolConfig,
+    // limits on how long upstreams get to connect and answer
+    pub timeouts: Timeouts,
+    // how failed upstream requests are retried
+    pub retry: RetryPolicy,
+    // headers merged into every request sent upstream
+    pub default_headers: DefaultHeaders,
+    // Via and X-Forwarded-* headers added to forwarded messages
+    pub forwarding: ForwardingConfig,
+    // who may use the proxy and which hosts it refuses to forward to
+    pub acl: Acl,
+    // routes requests to upstream clusters as a reverse proxy. None forwards them to
+    // wherever their URL or Host header points, as a forward proxy
+    pub routing: Option<RoutingTable>,
+    // env_logger filter used unless RUST_LOG is set, e.g. "info"
+    pub log_level: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0".to_string(),
+            port: "9095".to_string(),
+            admin_address: "127.0.0.1".to_string(),
+            admin_port: Some("9096".to_string()),
+            workers: 8,
+            max_connections: 10_000,
+            accept_backlog: 1024,
+            connect_ports: vec![443],
+            shutdown_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(60),
+            pool: PoolConfig::default(),
+            timeouts: Timeouts::default(),
+            retry: RetryPolicy::default(),
+            default_headers: DefaultHeaders::new(),
+            forwarding: ForwardingConfig::default(),
+            acl: Acl::default(),
+            routing: None,
+            log_level: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Config::default();
+        Self {
+            address: std::env::var("ADDRESS").unwrap_or(defaults.address),
+            port: std::env::var("PORT").unwrap_or(defaults.port),
+            admin_address: std::env::var("ADMIN_ADDRESS").unwrap_or(defaults.admin_address),
+            // an empty ADMIN_PORT turns the admin listener off
+            admin_port: match std::env::var("ADMIN_PORT") {
+                Ok(port) if port.is_empty() => None,
+                Ok(port) => Some(port),
+                Err(_) => defaults.admin_port,
+            },
+            workers: env_usize("WORKERS").unwrap_or(defaults.workers),
+            max_connections: env_usize("MAX_CONNECTIONS").unwrap_or(defaults.max_connections),
+            // QUEUE_SIZE is the name the setting had before the listener moved to tokio
+            accept_backlog: env_usize("ACCEPT_BACKLOG")
+                .or_else(|| env_usize("QUEUE_SIZE"))
+                .unwrap_or(defaults.accept_backlog),
+            connect_ports: env_ports("CONNECT_PORTS").unwrap_or(defaults.connect_ports),
+            shutdown_timeout: env_usize("SHUTDOWN_TIMEOUT")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(defaults.shutdown_timeout),
+            keep_alive_timeout: env_usize("KEEP_ALIVE_TIMEOUT")
+                .map(|secs| Duration::from_secs(secs as u64))
+                .unwrap_or(defaults.keep_alive_timeout),
+            pool: PoolConfig {
+                max_idle: env_usize("POOL_MAX_IDLE").unwrap_or(defaults.pool.max_idle),
+                max_idle_per_host: env_usize("POOL_MAX_IDLE_PER_HOST")
+                    .unwrap_or(defaults.pool.max_idle_per_host),
+                idle_timeout: env_usize("POOL_IDLE_TIMEOUT")
+                    .map(|secs| Duration::from_secs(secs as u64))
+                    .unwrap_or(defaults.pool.idle_timeout),
+            },
+            timeouts: Timeouts {
+                connect: env_secs("UPSTREAM_CONNECT_TIMEOUT").unwrap_or(defaults.timeouts.connect),
+                first_byte: env_secs("UPSTREAM_FIRST_BYTE_TIMEOUT")
+                    .unwrap_or(defaults.timeouts.first_byte),
+                idle_read: env_secs("UPSTREAM_IDLE_TIMEOUT").unwrap_or(defaults.timeouts.idle_read),
+                total: env_secs("UPSTREAM_TIMEOUT").unwrap_or(defaults.timeouts.total),
+            },
+            retry: RetryPolicy {
+                max_attempts: env_usize("RETRY_MAX_ATTEMPTS")
+                    .unwrap_or(defaults.retry.max_attempts),
+                base_backoff: env_millis("RETRY_BACKOFF_MS").unwrap_or(defaults.retry.base_backoff),
+                max_backoff: env_millis("RETRY_MAX_BACKOFF_MS")
+                    .unwrap_or(defaults.retry.max_backoff),
+                budget_percent: env_usize("RETRY_BUDGET_PERCENT")
+                    .unwrap_or(defaults.retry.budget_percent),
+                budget_min_retries: env_usize("RETRY_BUDGET_MIN_RETRIES")
+                    .unwrap_or(defaults.retry.budget_min_retries),
+                ..defaults.retry
+            },
+            default_headers: env_default_headers("DEFAULT_HEADERS")
+                .unwrap_or(defaults.default_headers),
+            forwarding: ForwardingConfig {
+                // an empty VIA turns the header off
+                via: match std::env::var("VIA") {
+                    Ok(via) if via.is_empty() => None,
+                    Ok(via) => Some(via),
+                    Err(_) => defaults.forwarding.via,
+                },
+                x_forwarded_for: env_bool("X_FORWARDED_FOR")
+                    .unwrap_or(defaults.forwarding.x_forwarded_for),
+                x_forwarded_proto: env_bool("X_FORWARDED_PROTO")
+                    .unwrap_or(defaults.forwarding.x_forwarded_proto),
+                forwarded: env_bool("FORWARDED").unwrap_or(defaults.forwarding.forwarded),
+            },
+            ..defaults
+        }
+    }
+}
+
+fn env_usize(key: &str) -> Option<usize> {
+    let value = std::env::var(key).ok()?;
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Some(n),
+        _ => {
+            log::warn!("ignoring invalid value for {}: {:?}", key, value);
+            None
+        }
+    }
+}
+
+fn env_bool(key: &str) -> Option<bool> {
+    let value = std::env::var(key).ok()?;
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" => Some(false),
+        _ => {
+            log::warn!("ignoring invalid value for {}: {:?}", key, value);
+            None
+        }
+    }
+}
+
+fn env_secs(key: &str) -> Option<Duration> {
+    env_usize(key).map(|secs| Duration::from_secs(secs as u64))
+}
+
+fn env_millis(key: &str) -> Option<Duration> {
+    env_usize(key).map(|millis| Duration::from_millis(millis as u64))
+}
+
+fn env_default_headers(key: &str) -> Option<DefaultHeaders> {
+    let value = std::env::var(key).ok()?;
+    match DefaultHeaders::parse(&value) {
+        Ok(headers) => Some(headers),
+        Err(e) => {
+            log::warn!("ignoring invalid value for {}: {}", key, e);
+            None
+        }
+    }
+}
+
+// comma separated list of ports, e.g. "443,8443"
+fn env_ports(key: &str) -> Option<Vec<u16>> {
+    let value = std::env::var(key).ok()?;
+    let ports: Result<Vec<u16>, _> = value
+        .split(',')
+        .map(|port| port.trim().parse::<u16>())
+        .collect();
+    match ports {
+        Ok(ports) => Some(ports),
+        Err(_) => {
+            log::warn!("ignoring invalid value for {}: {:?}", key, value);
+            None
+        }
+    }
+}
+
+#[test]
+fn test_env_usize() {
+    std::env::set_var("PROXYRS_TEST_WORKERS", "4");
+    assert_eq!(env_usize("PROXYRS_TEST_WORKERS"), Some(4));
+    std::env::set_var("PROXYRS_TEST_WORKERS", "0");
+    assert_eq!(env_usize("PROXYRS_TEST_WORKERS"), None);
+    std::env::set_var("PROXYRS_TEST_WORKERS", "many");
+    assert_eq!(env_usize("PROXYRS_TEST_WORKERS"), None);
+    std::env::remove_var("PROXYRS_TEST_WORKERS");
+    assert_eq!(env_usize("PROXYRS_TEST_WORKERS"), None);
+}
+
+#[test]
+fn test_env_bool() {
+    let tests = vec![
+        ("true", Some(true)),
+        ("On", Some(true)),
+        ("0", Some(false)),
+        ("no", Some(false)),
+        ("maybe", None),
+    ];
+
+    for (value, expected) in tests {
+        std::env::set_var("PROXYRS_TEST_BOOL", value);
+        assert_eq!(env_bool("PROXYRS_TEST_BOOL"), expected, "{}", value);
+    }
+    std::env::remove_var("PROXYRS_TEST_BOOL");
+    assert_eq!(env_bool("PROXYRS_TEST_BOOL"), None);
+}
+
+#[test]
+fn test_env_ports() {
+    std::env::set_var("PROXYRS_TEST_PORTS", "443, 8443");
+    assert_eq!(env_ports("PROXYRS_TEST_PORTS"), Some(vec![443, 8443]));
+    std::env::set_var("PROXYRS_TEST_PORTS", "443,https");
+    assert_eq!(env_ports("PROXYRS_TEST_PORTS"), None);
+    std::env::remove_var("PROXYRS_TEST_PORTS");
+    assert_eq!(env_ports("PROXYRS_TEST_PORTS"), None);
+}
@@ -0,0 +1,1065 @@
+use std::{error, fmt, path::Path, str::FromStr, time::Duration};
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{
+    acl::{Acl, Cidr},
+    balancer::{Endpoint, HashKey, Policy, MAX_WEIGHT},
+    config::Config,
+    connection_pool::PoolConfig,
+    default_headers::{DefaultHeaders, MergeMode},
+    forwarding::ForwardingConfig,
+    health::HealthCheck,
+    http_method::Method,
+    retry::RetryPolicy,
+    routing::{Cluster, PathMatch, Route, RoutingTable},
+    status_code::StatusCode,
+    timeout::Timeouts,
+};
+
+// a config file that could not be read or does not describe a valid config
+#[derive(Debug)]
+pub struct ConfigError {
+    pub file: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+impl error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    // picked by file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+// reads and validates the config file at path. whatever it leaves out keeps its default
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let error = |message: String| ConfigError {
+        file: path.display().to_string(),
+        message,
+    };
+    let format = Format::from_path(path)
+        .ok_or_else(|| error("unknown format, expected a .toml, .yaml or .yml file".to_string()))?;
+    let contents = std::fs::read_to_string(path).map_err(|e| error(e.to_string()))?;
+    parse(&contents, format).map_err(error)
+}
+
+pub fn parse(contents: &str, format: Format) -> Result<Config, String> {
+    let file: ConfigFile = match format {
+        Format::Toml => toml::from_str(contents).map_err(|e| e.to_string())?,
+        Format::Yaml => serde_yaml::from_str(contents).map_err(|e| e.to_string())?,
+    };
+    file.into_config()
+}
+
+// the layout of a config file, every field is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    listener: ListenerSection,
+    #[serde(default)]
+    admin: AdminSection,
+    #[serde(default)]
+    upstream: UpstreamSection,
+    #[serde(default)]
+    default_headers: Vec<DefaultHeaderEntry>,
+    #[serde(default)]
+    acl: AclSection,
+    #[serde(default)]
+    clusters: Vec<ClusterEntry>,
+    // any route turns on reverse proxy mode
+    #[serde(default)]
+    routes: Vec<RouteEntry>,
+    #[serde(default)]
+    logging: LoggingSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerSection {
+    address: Option<String>,
+    port: Option<u16>,
+    workers: Option<usize>,
+    max_connections: Option<usize>,
+    accept_backlog: Option<usize>,
+    connect_ports: Option<Vec<u16>>,
+    shutdown_timeout: Option<Seconds>,
+    keep_alive_timeout: Option<Seconds>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminSection {
+    enabled: Option<bool>,
+    address: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UpstreamSection {
+    #[serde(default)]
+    pool: PoolSection,
+    #[serde(default)]
+    timeouts: TimeoutsSection,
+    #[serde(default)]
+    retry: RetrySection,
+    #[serde(default)]
+    forwarding: ForwardingSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolSection {
+    max_idle: Option<usize>,
+    max_idle_per_host: Option<usize>,
+    idle_timeout: Option<Seconds>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsSection {
+    connect: Option<Seconds>,
+    first_byte: Option<Seconds>,
+    idle_read: Option<Seconds>,
+    total: Option<Seconds>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RetrySection {
+    max_attempts: Option<usize>,
+    backoff: Option<Seconds>,
+    max_backoff: Option<Seconds>,
+    statuses: Option<Vec<u32>>,
+    budget_percent: Option<usize>,
+    budget_min_retries: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ForwardingSection {
+    // empty to not send Via
+    via: Option<String>,
+    x_forwarded_for: Option<bool>,
+    x_forwarded_proto: Option<bool>,
+    forwarded: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DefaultHeaderEntry {
+    name: String,
+    value: String,
+    #[serde(default)]
+    mode: Mode,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Set,
+    Append,
+    #[default]
+    Default,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclSection {
+    #[serde(default)]
+    allow_clients: Vec<String>,
+    #[serde(default)]
+    deny_clients: Vec<String>,
+    #[serde(default)]
+    deny_hosts: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClusterEntry {
+    name: String,
+    #[serde(default)]
+    balancer: BalancerName,
+    // header or cookie consistent_hash hashes on
+    hash_header: Option<String>,
+    hash_cookie: Option<String>,
+    endpoints: Vec<EndpointEntry>,
+    // endpoints are only probed if set, an empty table probes with the defaults
+    health_check: Option<HealthCheckSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckSection {
+    path: Option<String>,
+    interval: Option<Seconds>,
+    timeout: Option<Seconds>,
+    healthy_threshold: Option<usize>,
+    unhealthy_threshold: Option<usize>,
+}
+
+impl HealthCheckSection {
+    fn into_health_check(self) -> Result<HealthCheck, String> {
+        let defaults = HealthCheck::default();
+        let path = self.path.unwrap_or(defaults.path);
+        if !path.starts_with('/') {
+            return Err(format!("path {:?} does not start with /", path));
+        }
+        Ok(HealthCheck {
+            path,
+            interval: positive_duration("interval", self.interval, defaults.interval)?,
+            timeout: positive_duration("timeout", self.timeout, defaults.timeout)?,
+            healthy_threshold: positive(
+                "healthy_threshold",
+                self.healthy_threshold,
+                defaults.healthy_threshold,
+            )?,
+            unhealthy_threshold: positive(
+                "unhealthy_threshold",
+                self.unhealthy_threshold,
+                defaults.unhealthy_threshold,
+            )?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BalancerName {
+    #[default]
+    RoundRobin,
+    Weighted,
+    LeastConnections,
+    RandomTwoChoices,
+    ConsistentHash,
+}
+
+// "host:port" or { address = "host:port", weight = 3 }
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum EndpointEntry {
+    Address(String),
+    Weighted(WeightedEndpoint),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WeightedEndpoint {
+    address: String,
+    #[serde(default = "default_weight")]
+    weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl ClusterEntry {
+    fn into_cluster(self) -> Result<Cluster, String> {
+        let policy = match (self.balancer, self.hash_header, self.hash_cookie) {
+            (BalancerName::ConsistentHash, Some(header), None) => {
+                Policy::ConsistentHash(HashKey::Header(header))
+            }
+            (BalancerName::ConsistentHash, None, Some(cookie)) => {
+                Policy::ConsistentHash(HashKey::Cookie(cookie))
+            }
+            (BalancerName::ConsistentHash, _, _) => {
+                return Err(format!(
+                    "cluster {:?}: consistent_hash needs either hash_header or hash_cookie",
+                    self.name
+                ))
+            }
+            (_, Some(_), _) | (_, _, Some(_)) => {
+                return Err(format!(
+                    "cluster {:?}: hash_header and hash_cookie need the consistent_hash balancer",
+                    self.name
+                ))
+            }
+            (BalancerName::RoundRobin, None, None) => Policy::RoundRobin,
+            (BalancerName::Weighted, None, None) => Policy::Weighted,
+            (BalancerName::LeastConnections, None, None) => Policy::LeastConnections,
+            (BalancerName::RandomTwoChoices, None, None) => Policy::RandomTwoChoices,
+        };
+        let endpoints = self
+            .endpoints
+            .into_iter()
+            .map(|endpoint| match endpoint {
+                EndpointEntry::Address(address) => Ok(Endpoint::new(address, 1)),
+                EndpointEntry::Weighted(endpoint) if endpoint.weight > MAX_WEIGHT => Err(format!(
+                    "cluster {:?}: endpoint {} weight must be at most {}",
+                    self.name, endpoint.address, MAX_WEIGHT
+                )),
+                EndpointEntry::Weighted(endpoint) => {
+                    Ok(Endpoint::new(endpoint.address, endpoint.weight))
+                }
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let health_check = match self.health_check {
+            Some(health_check) => Some(
+                health_check
+                    .into_health_check()
+                    .map_err(|e| format!("cluster {:?}: health_check.{}", self.name, e))?,
+            ),
+            None => None,
+        };
+        let cluster = Cluster::new(self.name, policy, endpoints)?;
+        Ok(match health_check {
+            Some(health_check) => cluster.with_health_check(health_check),
+            None => cluster,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteEntry {
+    host: Option<String>,
+    path_prefix: Option<String>,
+    path_regex: Option<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    cluster: String,
+    #[serde(default)]
+    strip_prefix: bool,
+    rewrite: Option<String>,
+}
+
+impl RouteEntry {
+    fn into_route(self) -> Result<Route, String> {
+        let path = match (self.path_prefix, self.path_regex) {
+            (Some(_), Some(_)) => return Err("path_prefix and path_regex both set".to_string()),
+            (Some(prefix), None) if !prefix.starts_with('/') => {
+                return Err(format!("path_prefix {:?} does not start with /", prefix))
+            }
+            (Some(prefix), None) => PathMatch::Prefix(prefix),
+            (None, Some(regex)) => {
+                PathMatch::regex(&regex).map_err(|e| format!("path_regex: {}", e))?
+            }
+            (None, None) => PathMatch::Any,
+        };
+        let rewrite = match (self.strip_prefix, self.rewrite) {
+            (true, Some(_)) => return Err("strip_prefix and rewrite both set".to_string()),
+            (true, None) if !matches!(path, PathMatch::Prefix(_)) => {
+                return Err("strip_prefix needs a path_prefix".to_string())
+            }
+            (true, None) => Some(String::new()),
+            (false, rewrite) => rewrite,
+        };
+        let methods = self
+            .methods
+            .iter()
+            .map(|method| Method::from_str(method).map_err(|e| e.to_string()))
+            .collect::<Result<_, _>>()?;
+        Ok(Route {
+            host: self.host,
+            path,
+            methods,
+            cluster: self.cluster,
+            rewrite,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoggingSection {
+    // env_logger filter, e.g. "info" or "warn,proxyrs=debug"
+    level: Option<String>,
+}
+
+// a duration given as whole seconds or as a number with a unit, e.g. "250ms", "10s", "5m"
+#[derive(Debug, Clone, Copy)]
+struct Seconds(Duration);
+
+impl<'de> Deserialize<'de> for Seconds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Seconds;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a duration like 30, \"250ms\", \"10s\", \"5m\" or \"1h\"")
+            }
+
+            fn visit_u64<E: de::Error>(self, secs: u64) -> Result<Seconds, E> {
+                Ok(Seconds(Duration::from_secs(secs)))
+            }
+
+            fn visit_i64<E: de::Error>(self, secs: i64) -> Result<Seconds, E> {
+                u64::try_from(secs)
+                    .map(|secs| Seconds(Duration::from_secs(secs)))
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(secs), &self))
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Seconds, E> {
+                parse_duration(value)
+                    .map(Seconds)
+                    .ok_or_else(|| E::invalid_value(de::Unexpected::Str(value), &self))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    match unit.trim() {
+        "ms" => Some(Duration::from_millis(amount)),
+        "" | "s" => Some(Duration::from_secs(amount)),
+        // None for amounts that don't fit in u64 seconds
+        "m" => amount.checked_mul(60).map(Duration::from_secs),
+        "h" => amount.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
+// a value that must not be zero
+fn positive(key: &str, value: Option<usize>, default: usize) -> Result<usize, String> {
+    match value {
+        Some(0) => Err(format!("{}: must be greater than 0", key)),
+        Some(value) => Ok(value),
+        None => Ok(default),
+    }
+}
+
+fn positive_duration(
+    key: &str,
+    value: Option<Seconds>,
+    default: Duration,
+) -> Result<Duration, String> {
+    match value {
+        Some(Seconds(value)) if value.is_zero() => Err(format!("{}: must be greater than 0", key)),
+        Some(Seconds(value)) => Ok(value),
+        None => Ok(default),
+    }
+}
+
+fn cidrs(key: &str, values: &[String]) -> Result<Vec<Cidr>, String> {
+    values
+        .iter()
+        .map(|value| Cidr::parse(value).map_err(|e| format!("{}: {}", key, e)))
+        .collect()
+}
+
+impl ConfigFile {
+    fn into_config(self) -> Result<Config, String> {
+        let defaults = Config::default();
+        let listener = self.listener;
+        let admin = self.admin;
+        let upstream = self.upstream;
+
+        let mut default_headers = DefaultHeaders::new();
+        for header in self.default_headers {
+            let mode = match header.mode {
+                Mode::Set => MergeMode::Override,
+                Mode::Append => MergeMode::Append,
+                Mode::Default => MergeMode::IfAbsent,
+            };
+            default_headers
+                .add(header.name, header.value, mode)
+                .map_err(|e| format!("default_headers: {}", e))?;
+        }
+
+        let retry_statuses = match upstream.retry.statuses {
+            Some(statuses) => statuses
+                .into_iter()
+                .map(|status| {
+                    StatusCode::from_u32(status)
+                        .map_err(|_| format!("upstream.retry.statuses: invalid status {}", status))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => defaults.retry.retry_statuses.clone(),
+        };
+        let budget_percent = upstream
+            .retry
+            .budget_percent
+            .unwrap_or(defaults.retry.budget_percent);
+        if budget_percent > 100 {
+            return Err("upstream.retry.budget_percent: must be at most 100".to_string());
+        }
+
+        // clusters are checked even without routes, a cluster no route sends
+        // requests to is most likely a mistake
+        let clusters: Vec<Cluster> = self
+            .clusters
+            .into_iter()
+            .map(ClusterEntry::into_cluster)
+            .collect::<Result<_, _>>()
+            .map_err(|e| format!("clusters: {}", e))?;
+        let routes: Vec<Route> = self
+            .routes
+            .into_iter()
+            .enumerate()
+            .map(|(i, route)| {
+                route
+                    .into_route()
+                    .map_err(|e| format!("routes[{}]: {}", i, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let unused = clusters
+            .iter()
+            .find(|cluster| !routes.iter().any(|route| route.cluster == cluster.name))
+            .map(|cluster| cluster.name.clone());
+        let routing = if routes.is_empty() && clusters.is_empty() {
+            None
+        } else {
+            Some(RoutingTable::new(routes, clusters).map_err(|e| format!("routes: {}", e))?)
+        };
+        if let Some(unused) = unused {
+            return Err(format!("clusters: no route to cluster {:?}", unused));
+        }
+
+        let log_level = match self.logging.level {
+            Some(level) => {
+                validate_log_filter(&level).map_err(|e| format!("logging.level: {}", e))?;
+                Some(level)
+            }
+            None => defaults.log_level.clone(),
+        };
+
+        Ok(Config {
+            address: listener.address.unwrap_or(defaults.address),
+            port: listener
+                .port
+                .map(|port| port.to_string())
+                .unwrap_or(defaults.port),
+            admin_address: admin.address.unwrap_or(defaults.admin_address),
+            admin_port: match (admin.enabled, admin.port) {
+                (Some(false), _) => None,
+                (_, Some(port)) => Some(port.to_string()),
+                (_, None) => defaults.admin_port,
+            },
+            workers: positive("listener.workers", listener.workers, defaults.workers)?,
+            max_connections: positive(
+                "listener.max_connections",
+                listener.max_connections,
+                defaults.max_connections,
+            )?,
+            accept_backlog: positive(
+                "listener.accept_backlog",
+                listener.accept_backlog,
+                defaults.accept_backlog,
+            )?,
+            connect_ports: listener.connect_ports.unwrap_or(defaults.connect_ports),
+            shutdown_timeout: positive_duration(
+                "listener.shutdown_timeout",
+                listener.shutdown_timeout,
+                defaults.shutdown_timeout,
+            )?,
+            keep_alive_timeout: positive_duration(
+                "listener.keep_alive_timeout",
+                listener.keep_alive_timeout,
+                defaults.keep_alive_timeout,
+            )?,
+            pool: PoolConfig {
+                max_idle: upstream.pool.max_idle.unwrap_or(defaults.pool.max_idle),
+                max_idle_per_host: upstream
+                    .pool
+                    .max_idle_per_host
+                    .unwrap_or(defaults.pool.max_idle_per_host),
+                idle_timeout: upstream
+                    .pool
+                    .idle_timeout
+                    .map_or(defaults.pool.idle_timeout, |s| s.0),
+            },
+            timeouts: Timeouts {
+                connect: positive_duration(
+                    "upstream.timeouts.connect",
+                    upstream.timeouts.connect,
+                    defaults.timeouts.connect,
+                )?,
+                first_byte: positive_duration(
+                    "upstream.timeouts.first_byte",
+                    upstream.timeouts.first_byte,
+                    defaults.timeouts.first_byte,
+                )?,
+                idle_read: positive_duration(
+                    "upstream.timeouts.idle_read",
+                    upstream.timeouts.idle_read,
+                    defaults.timeouts.idle_read,
+                )?,
+                total: positive_duration(
+                    "upstream.timeouts.total",
+                    upstream.timeouts.total,
+                    defaults.timeouts.total,
+                )?,
+            },
+            retry: RetryPolicy {
+                max_attempts: positive(
+                    "upstream.retry.max_attempts",
+                    upstream.retry.max_attempts,
+                    defaults.retry.max_attempts,
+                )?,
+                base_backoff: upstream
+                    .retry
+                    .backoff
+                    .map_or(defaults.retry.base_backoff, |s| s.0),
+                max_backoff: upstream
+                    .retry
+                    .max_backoff
+                    .map_or(defaults.retry.max_backoff, |s| s.0),
+                retry_statuses,
+                budget_percent,
+                budget_min_retries: upstream
+                    .retry
+                    .budget_min_retries
+                    .unwrap_or(defaults.retry.budget_min_retries),
+                ..defaults.retry
+            },
+            default_headers,
+            forwarding: ForwardingConfig {
+                via: match upstream.forwarding.via {
+                    Some(via) if via.is_empty() => None,
+                    Some(via) => Some(via),
+                    None => defaults.forwarding.via,
+                },
+                x_forwarded_for: upstream
+                    .forwarding
+                    .x_forwarded_for
+                    .unwrap_or(defaults.forwarding.x_forwarded_for),
+                x_forwarded_proto: upstream
+                    .forwarding
+                    .x_forwarded_proto
+                    .unwrap_or(defaults.forwarding.x_forwarded_proto),
+                forwarded: upstream
+                    .forwarding
+                    .forwarded
+                    .unwrap_or(defaults.forwarding.forwarded),
+            },
+            acl: Acl {
+                allow_clients: cidrs("acl.allow_clients", &self.acl.allow_clients)?,
+                deny_clients: cidrs("acl.deny_clients", &self.acl.deny_clients)?,
+                deny_hosts: self.acl.deny_hosts,
+            },
+            routing,
+            log_level,
+        })
+    }
+}
+
+// checks an env_logger filter like "warn,proxyrs=debug". a directive without "="
+// is either a level or a module that logs everything
+fn validate_log_filter(filter: &str) -> Result<(), String> {
+    for directive in filter.split(',').map(str::trim) {
+        if directive.is_empty() {
+            return Err(format!("empty directive in {:?}", filter));
+        }
+        if let Some((_, level)) = directive.split_once('=') {
+            log::LevelFilter::from_str(level)
+                .map_err(|_| format!("invalid level {:?} in {:?}", level, filter))?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_parse_duration() {
+    let tests = vec![
+        ("30", Some(Duration::from_secs(30))),
+        ("250ms", Some(Duration::from_millis(250))),
+        ("10s", Some(Duration::from_secs(10))),
+        ("5m", Some(Duration::from_secs(300))),
+        ("1h", Some(Duration::from_secs(3600))),
+        ("10 s", Some(Duration::from_secs(10))),
+        ("", None),
+        ("s", None),
+        ("1d", None),
+        ("-1s", None),
+        ("1.5s", None),
+        ("999999999999999999h", None),
+        ("999999999999999999m", None),
+        ("99999999999999999999s", None),
+    ];
+
+    for (value, expected) in tests {
+        assert_eq!(parse_duration(value), expected, "{:?}", value);
+    }
+}
+
+#[test]
+fn test_parse_toml() {
+    let config = parse(
+        r#"
+            [listener]
+            port = 8080
+            workers = 2
+            connect_ports = [443, 8443]
+            keep_alive_timeout = "90s"
+
+            [admin]
+            enabled = false
+
+            [upstream.timeouts]
+            connect = "500ms"
+            total = 30
+
+            [upstream.retry]
+            max_attempts = 5
+            statuses = [503]
+
+            [upstream.forwarding]
+            via = ""
+
+            [[default_headers]]
+            name = "X-Env"
+            value = "prod"
+            mode = "set"
+
+            [acl]
+            allow_clients = ["10.0.0.0/8"]
+            deny_hosts = ["*.internal"]
+
+            [logging]
+            level = "warn,proxyrs=debug"
+        "#,
+        Format::Toml,
+    )
+    .unwrap();
+
+    let defaults = Config::default();
+    assert_eq!(config.port, "8080");
+    assert_eq!(config.address, defaults.address);
+    assert_eq!(config.workers, 2);
+    assert_eq!(config.connect_ports, vec![443, 8443]);
+    assert_eq!(config.keep_alive_timeout, Duration::from_secs(90));
+    assert_eq!(config.admin_port, None);
+    assert_eq!(config.timeouts.connect, Duration::from_millis(500));
+    assert_eq!(config.timeouts.total, Duration::from_secs(30));
+    assert_eq!(config.timeouts.first_byte, defaults.timeouts.first_byte);
+    assert_eq!(config.retry.max_attempts, 5);
+    assert_eq!(
+        config.retry.retry_statuses,
+        vec![StatusCode::ServiceUnavailable]
+    );
+    assert_eq!(config.forwarding.via, None);
+    assert_eq!(config.default_headers.iter().count(), 1);
+    assert!(!config.acl.allows_client("192.0.2.1".parse().unwrap()));
+    assert!(!config.acl.allows_host("db.internal"));
+    assert_eq!(config.log_level.as_deref(), Some("warn,proxyrs=debug"));
+}
+
+#[test]
+fn test_parse_yaml() {
+    let config = parse(
+        "
+listener:
+  port: 8080
+  max_connections: 100
+  accept_backlog: 256
+admin:
+  port: 9100
+upstream:
+  pool:
+    idle_timeout: 5m
+default_headers:
+  - name: X-Env
+    value: prod
+acl:
+  deny_clients: [\"192.0.2.0/24\"]
+",
+        Format::Yaml,
+    )
+    .unwrap();
+
+    assert_eq!(config.port, "8080");
+    assert_eq!(config.max_connections, 100);
+    assert_eq!(config.accept_backlog, 256);
+    assert_eq!(config.admin_port.as_deref(), Some("9100"));
+    assert_eq!(config.pool.idle_timeout, Duration::from_secs(300));
+    assert_eq!(config.default_headers.iter().count(), 1);
+    assert!(!config.acl.allows_client("192.0.2.1".parse().unwrap()));
+
+    assert_eq!(
+        parse("", Format::Toml).unwrap().port,
+        Config::default().port
+    );
+}
+
+#[test]
+fn test_parse_invalid() {
+    let tests = vec![
+        ("unknown key", "[listener]\nprot = 8080", "prot"),
+        ("unknown section", "[upstreams]", "upstreams"),
+        ("port out of range", "[listener]\nport = 70000", "port"),
+        (
+            "zero workers",
+            "[listener]\nworkers = 0",
+            "listener.workers",
+        ),
+        (
+            "bad duration",
+            "[upstream.timeouts]\nconnect = \"10 parsecs\"",
+            "duration",
+        ),
+        (
+            "zero connect timeout",
+            "[upstream.timeouts]\nconnect = 0",
+            "upstream.timeouts.connect",
+        ),
+        (
+            "zero total timeout",
+            "[upstream.timeouts]\ntotal = \"0ms\"",
+            "upstream.timeouts.total",
+        ),
+        (
+            "zero keep-alive timeout",
+            "[listener]\nkeep_alive_timeout = 0",
+            "listener.keep_alive_timeout",
+        ),
+        (
+            "zero shutdown timeout",
+            "[listener]\nshutdown_timeout = \"0s\"",
+            "listener.shutdown_timeout",
+        ),
+        ("bad status", "[upstream.retry]\nstatuses = [600]", "600"),
+        (
+            "budget",
+            "[upstream.retry]\nbudget_percent = 150",
+            "budget_percent",
+        ),
+        (
+            "bad cidr",
+            "[acl]\nallow_clients = [\"10.0.0.0/40\"]",
+            "acl.allow_clients",
+        ),
+        (
+            "reserved header",
+            "[[default_headers]]\nname = \"Host\"\nvalue = \"x\"",
+            "default_headers",
+        ),
+        (
+            "bad mode",
+            "[[default_headers]]\nname = \"A\"\nvalue = \"b\"\nmode = \"replace\"",
+            "replace",
+        ),
+        (
+            "bad log level",
+            "[logging]\nlevel = \"proxyrs=loud\"",
+            "loud",
+        ),
+    ];
+
+    for (name, contents, expected) in tests {
+        let error = parse(contents, Format::Toml).unwrap_err();
+        assert!(error.contains(expected), "{}: {}", name, error);
+    }
+}
+
+#[test]
+fn test_parse_routes() {
+    let config = parse(
+        r#"
+            [[clusters]]
+            name = "users"
+            endpoints = ["10.0.0.1:8080", "10.0.0.2:8080"]
+
+            [[routes]]
+            host = "api.example.com"
+            path_prefix = "/users"
+            methods = ["GET"]
+            strip_prefix = true
+            cluster = "users"
+        "#,
+        Format::Toml,
+    )
+    .unwrap();
+    let routing = config.routing.unwrap();
+    assert_eq!(routing.clusters()[0].balancer.endpoints().len(), 2);
+
+    let request = "GET /users/1 HTTP/1.1\r\nHost: api.example.com\r\n\r\n";
+    let request = crate::http_request::HttpRequest::from_stream(&mut request.as_bytes()).unwrap();
+    let (cluster, target) = routing.route(&request).unwrap();
+    assert_eq!(cluster.name, "users");
+    assert_eq!(target, "/1");
+
+    assert!(parse("", Format::Toml).unwrap().routing.is_none());
+
+    let cluster = "[[clusters]]\nname = \"users\"\nendpoints = [\"users:80\"]\n";
+    let tests = vec![
+        ("unknown cluster", "[[routes]]\ncluster = \"orders\"", "orders"),
+        (
+            "prefix and regex",
+            "[[routes]]\ncluster = \"users\"\npath_prefix = \"/a\"\npath_regex = \"/b\"",
+            "routes[0]",
+        ),
+        (
+            "strip without prefix",
+            "[[routes]]\ncluster = \"users\"\nstrip_prefix = true",
+            "strip_prefix",
+        ),
+        (
+            "relative prefix",
+            "[[routes]]\ncluster = \"users\"\npath_prefix = \"users\"",
+            "path_prefix",
+        ),
+        (
+            "bad regex",
+            "[[routes]]\ncluster = \"users\"\npath_regex = \"/(\"",
+            "path_regex",
+        ),
+        (
+            "bad endpoint",
+            "[[clusters]]\nname = \"orders\"\nendpoints = [\"orders\"]\n[[routes]]\ncluster = \"orders\"",
+            "clusters",
+        ),
+    ];
+    for (name, routes, expected) in tests {
+        let contents = format!("{}{}", cluster, routes);
+        let error = parse(&contents, Format::Toml).unwrap_err();
+        assert!(error.contains(expected), "{}: {}", name, error);
+    }
+
+    // clusters are checked even when there are no routes
+    let tests = vec![
+        ("no route", cluster.to_string(), "no route to cluster \"users\""),
+        (
+            "broken cluster without routes",
+            "[[clusters]]\nname = \"users\"\nendpoints = []\n".to_string(),
+            "clusters",
+        ),
+        (
+            "unused cluster",
+            format!(
+                "{}[[clusters]]\nname = \"orders\"\nendpoints = [\"orders:80\"]\n[[routes]]\ncluster = \"users\"",
+                cluster
+            ),
+            "no route to cluster \"orders\"",
+        ),
+    ];
+    for (name, contents, expected) in tests {
+        let error = parse(&contents, Format::Toml).unwrap_err();
+        assert!(error.contains(expected), "{}: {}", name, error);
+    }
+}
+
+#[test]
+fn test_parse_clusters() {
+    let config = parse(
+        r#"
+            clusters:
+              - name: users
+                balancer: consistent_hash
+                hash_cookie: session
+                endpoints:
+                  - 10.0.0.1:8080
+                  - address: 10.0.0.2:8080
+                    weight: 3
+                health_check:
+                  path: /status
+                  interval: 5s
+                  unhealthy_threshold: 2
+              - name: orders
+                balancer: least_connections
+                endpoints: ["10.0.1.1:8080"]
+            routes:
+              - cluster: users
+                path_prefix: /users
+              - cluster: orders
+        "#,
+        Format::Yaml,
+    )
+    .unwrap();
+    let routing = config.routing.unwrap();
+    let users = &routing.clusters()[0].balancer;
+    assert_eq!(
+        users.policy(),
+        &Policy::ConsistentHash(HashKey::Cookie("session".to_string()))
+    );
+    let weights: Vec<u32> = users.endpoints().iter().map(|e| e.weight).collect();
+    assert_eq!(weights, vec![1, 3]);
+    assert_eq!(
+        routing.clusters()[1].balancer.policy(),
+        &Policy::LeastConnections
+    );
+    assert_eq!(
+        routing.clusters()[0].health_check,
+        Some(HealthCheck {
+            path: "/status".to_string(),
+            interval: Duration::from_secs(5),
+            unhealthy_threshold: 2,
+            ..HealthCheck::default()
+        })
+    );
+    assert_eq!(routing.clusters()[1].health_check, None);
+
+    let route = "[[routes]]\ncluster = \"users\"\n";
+    let tests = vec![
+        (
+            "unknown balancer",
+            "balancer = \"fastest\"\nendpoints = [\"a:80\"]",
+            "fastest",
+        ),
+        (
+            "hash without key",
+            "balancer = \"consistent_hash\"\nendpoints = [\"a:80\"]",
+            "hash_header",
+        ),
+        (
+            "key without hash",
+            "hash_header = \"X-User\"\nendpoints = [\"a:80\"]",
+            "consistent_hash",
+        ),
+        (
+            "zero weight",
+            "endpoints = [{ address = \"a:80\", weight = 0 }]",
+            "weight 0",
+        ),
+        (
+            "huge weight",
+            "endpoints = [{ address = \"a:80\", weight = 4000000000 }]",
+            "at most 10000",
+        ),
+        ("no endpoints", "endpoints = []", "no endpoints"),
+        (
+            "relative health check path",
+            "endpoints = [\"a:80\"]\nhealth_check = { path = \"healthz\" }",
+            "health_check.path",
+        ),
+        (
+            "zero health check interval",
+            "endpoints = [\"a:80\"]\nhealth_check = { interval = 0 }",
+            "health_check.interval",
+        ),
+        (
+            "zero threshold",
+            "endpoints = [\"a:80\"]\nhealth_check = { unhealthy_threshold = 0 }",
+            "health_check.unhealthy_threshold",
+        ),
+        (
+            "unknown health check key",
+            "endpoints = [\"a:80\"]\nhealth_check = { method = \"HEAD\" }",
+            "method",
+        ),
+    ];
+    for (name, cluster, expected) in tests {
+        let contents = format!("{}[[clusters]]\nname = \"users\"\n{}", route, cluster);
+        let error = parse(&contents, Format::Toml).unwrap_err();
+        assert!(error.contains(expected), "{}: {}", name, error);
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::net::TcpStream;
+
+// limits for idle upstream connections
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    // idle connections kept across all hosts
+    pub max_idle: usize,
+    // idle connections kept for a single (host, port)
+    pub max_idle_per_host: usize,
+    // idle connections older than this are closed instead of reused
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 100,
+            max_idle_per_host: 10,
+            idle_timeout: Duration::from_secs(90),
+        }
+    }
+}
+
+// counters to tune the pool with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolStats {
+    // checkouts served by an idle connection
+    pub hits: u64,
+    // checkouts that had to open a new connection
+    pub misses: u64,
+    // idle connections closed because they expired, went stale or did not fit
+    pub evictions: u64,
+}
+
+struct IdleConnection {
+    stream: TcpStream,
+    idle_since: Instant,
+}
+
+// idle upstream connections keyed by (host, port)
+pub struct ConnectionPool {
+    config: PoolConfig,
+    idle: Mutex<HashMap<(String, u16), Vec<IdleConnection>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> Self {
+        Self {
+            config,
+            idle: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    // take the most recently used idle connection to host:port, if one is still usable
+    pub fn checkout(&self, host: &str, port: u16) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let key = (host.to_string(), port);
+        let mut found = None;
+        if let Some(connections) = idle.get_mut(&key) {
+            while let Some(connection) = connections.pop() {
+                if self.is_usable(&connection) {
+                    found = Some(connection.stream);
+                    break;
+                }
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+            if connections.is_empty() {
+                idle.remove(&key);
+            }
+        }
+
+        match found {
+            Some(stream) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                Some(stream)
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    // hand a connection back after a response that allows reuse. expired and closed
+    // connections are dropped first, so they don't take up room under the limits
+    pub fn checkin(&self, host: &str, port: u16, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        idle.retain(|_, connections| {
+            let before = connections.len();
+            connections.retain(|connection| self.is_usable(connection));
+            self.evictions
+                .fetch_add((before - connections.len()) as u64, Ordering::Relaxed);
+            !connections.is_empty()
+        });
+
+        let key = (host.to_string(), port);
+        let total: usize = idle.values().map(|connections| connections.len()).sum();
+        let for_host = idle.get(&key).map_or(0, |connections| connections.len());
+        if total >= self.config.max_idle || for_host >= self.config.max_idle_per_host {
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        idle.entry(key).or_default().push(IdleConnection {
+            stream,
+            idle_since: Instant::now(),
+        });
+    }
+
+    fn is_usable(&self, connection: &IdleConnection) -> bool {
+        connection.idle_since.elapsed() < self.config.idle_timeout && is_open(&connection.stream)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// an idle connection is only usable if the upstream neither closed it nor sent anything
+fn is_open(stream: &TcpStream) -> bool {
+    let mut buffer = [0; 1];
+    matches!(stream.try_read(&mut buffer), Err(e) if e.kind() == ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+async fn connected_pair(listener: &tokio::net::TcpListener) -> (TcpStream, TcpStream) {
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (client, server)
+}
+
+#[tokio::test]
+async fn test_pool_reuses_connections() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool = ConnectionPool::new(PoolConfig::default());
+
+    assert!(pool.checkout("example.com", 80).is_none());
+    let (client, _server) = connected_pair(&listener).await;
+    pool.checkin("example.com", 80, client);
+
+    assert!(pool.checkout("example.com", 8080).is_none());
+    assert!(pool.checkout("example.com", 80).is_some());
+    assert!(pool.checkout("example.com", 80).is_none());
+    assert_eq!(
+        pool.stats(),
+        PoolStats {
+            hits: 1,
+            misses: 3,
+            evictions: 0
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_pool_evicts_closed_and_expired_connections() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool = ConnectionPool::new(PoolConfig {
+        idle_timeout: Duration::from_millis(50),
+        ..PoolConfig::default()
+    });
+
+    // upstream closed the connection while it was idle
+    let (client, server) = connected_pair(&listener).await;
+    pool.checkin("example.com", 80, client);
+    drop(server);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert!(pool.checkout("example.com", 80).is_none());
+
+    // connection sat idle for longer than the timeout
+    let (client, _server) = connected_pair(&listener).await;
+    pool.checkin("example.com", 80, client);
+    tokio::time::sleep(Duration::from_millis(60)).await;
+    assert!(pool.checkout("example.com", 80).is_none());
+
+    assert_eq!(pool.stats().evictions, 2);
+}
+
+#[tokio::test]
+async fn test_pool_limits() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool = ConnectionPool::new(PoolConfig {
+        max_idle: 3,
+        max_idle_per_host: 2,
+        ..PoolConfig::default()
+    });
+
+    let mut servers = Vec::new();
+    for host in [
+        "a.example.com",
+        "a.example.com",
+        "a.example.com",
+        "b.example.com",
+        "c.example.com",
+    ] {
+        let (client, server) = connected_pair(&listener).await;
+        servers.push(server);
+        pool.checkin(host, 80, client);
+    }
+
+    // third connection for a.example.com exceeds the per host limit,
+    // c.example.com exceeds the total limit
+    assert_eq!(pool.stats().evictions, 2);
+    assert!(pool.checkout("a.example.com", 80).is_some());
+    assert!(pool.checkout("a.example.com", 80).is_some());
+    assert!(pool.checkout("b.example.com", 80).is_some());
+    assert!(pool.checkout("c.example.com", 80).is_none());
+}
+
+#[tokio::test]
+async fn test_pool_makes_room_for_stale_connections() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let pool = ConnectionPool::new(PoolConfig {
+        max_idle: 2,
+        max_idle_per_host: 1,
+        ..PoolConfig::default()
+    });
+
+    // the pool is full of connections their upstreams closed while they were idle
+    for host in ["a.example.com", "b.example.com"] {
+        let (client, server) = connected_pair(&listener).await;
+        pool.checkin(host, 80, client);
+        drop(server);
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    let (client, _server) = connected_pair(&listener).await;
+    pool.checkin("c.example.com", 80, client);
+    assert_eq!(pool.stats().evictions, 2);
+    assert!(pool.checkout("c.example.com", 80).is_some());
+
+    // a rejected connection leaves no entry for its host behind
+    let (first, _first_server) = connected_pair(&listener).await;
+    let (second, _second_server) = connected_pair(&listener).await;
+    pool.checkin("d.example.com", 80, first);
+    pool.checkin("e.example.com", 80, second);
+    let (third, _third_server) = connected_pair(&listener).await;
+    pool.checkin("f.example.com", 80, third);
+    let idle = pool.idle.lock().unwrap();
+    assert!(!idle.contains_key(&("f.example.com".to_string(), 80)));
+}
@@ -0,0 +1,196 @@
+use crate::header_map::HeaderMap;
+
+// how a default header is merged with the headers a request already has
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MergeMode {
+    // replaces every value the request has
+    Override,
+    // added after the values the request has
+    Append,
+    // only added if the request has no value
+    IfAbsent,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DefaultHeader {
+    pub name: String,
+    pub value: String,
+    pub mode: MergeMode,
+}
+
+// headers stamped on every request sent upstream, e.g. an org id or a fixed User-Agent
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DefaultHeaders {
+    headers: Vec<DefaultHeader>,
+}
+
+// these describe the message or the connection it travels on and can't be defaulted
+const RESERVED: [&str; 4] = ["Host", "Content-Length", "Transfer-Encoding", "Connection"];
+
+impl DefaultHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // parses "|" separated entries of the form "[mode] Name: value", where mode is
+    // set, append or default (the default). e.g.
+    // "set X-Org-Id: 42|default User-Agent: proxyrs"
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut headers = DefaultHeaders::new();
+        for entry in spec
+            .split('|')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (mode, header) = match entry.split_once(' ') {
+                Some(("set", header)) => (MergeMode::Override, header),
+                Some(("append", header)) => (MergeMode::Append, header),
+                Some(("default", header)) => (MergeMode::IfAbsent, header),
+                _ => (MergeMode::IfAbsent, entry),
+            };
+            let (name, value) = header
+                .split_once(':')
+                .ok_or_else(|| format!("missing ':' in {:?}", entry))?;
+            headers.add(name.trim(), value.trim(), mode)?;
+        }
+        Ok(headers)
+    }
+
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+        mode: MergeMode,
+    ) -> Result<(), String> {
+        let name = name.into();
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(format!("invalid header name {:?}", name));
+        }
+        if RESERVED
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(&name))
+        {
+            return Err(format!("{} can't be set as a default header", name));
+        }
+        self.headers.push(DefaultHeader {
+            name,
+            value: value.into(),
+            mode,
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DefaultHeader> {
+        self.headers.iter()
+    }
+
+    // merges the defaults into headers in the order they were added
+    pub fn apply(&self, headers: &mut HeaderMap) {
+        for header in &self.headers {
+            match header.mode {
+                MergeMode::Override => headers.insert(&header.name, &header.value),
+                MergeMode::Append => headers.append(&header.name, &header.value),
+                MergeMode::IfAbsent => headers.insert_if_absent(&header.name, &header.value),
+            }
+        }
+    }
+}
+
+// a plain map of defaults only fills in headers a request does not have
+impl From<HeaderMap> for DefaultHeaders {
+    fn from(headers: HeaderMap) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| DefaultHeader {
+                name: name.to_string(),
+                value: value.to_string(),
+                mode: MergeMode::IfAbsent,
+            })
+            .collect();
+        Self { headers }
+    }
+}
+
+#[test]
+fn test_parse_default_headers() {
+    struct TestCase {
+        _name: &'static str,
+        input: &'static str,
+        expected: Option<Vec<(&'static str, &'static str, MergeMode)>>,
+    }
+
+    let tests = vec![
+        TestCase {
+            _name: "empty",
+            input: "",
+            expected: Some(vec![]),
+        },
+        TestCase {
+            _name: "every mode",
+            input: "set X-Org-Id: 42 | append Via: 1.1 edge|User-Agent: proxyrs/0.1 (linux)",
+            expected: Some(vec![
+                ("X-Org-Id", "42", MergeMode::Override),
+                ("Via", "1.1 edge", MergeMode::Append),
+                ("User-Agent", "proxyrs/0.1 (linux)", MergeMode::IfAbsent),
+            ]),
+        },
+        TestCase {
+            _name: "no value",
+            input: "X-Org-Id",
+            expected: None,
+        },
+        TestCase {
+            _name: "framing header",
+            input: "set Content-Length: 0",
+            expected: None,
+        },
+    ];
+
+    for test in tests {
+        let actual = DefaultHeaders::parse(test.input).ok().map(|headers| {
+            headers
+                .iter()
+                .map(|header| (header.name.clone(), header.value.clone(), header.mode))
+                .collect::<Vec<_>>()
+        });
+        let expected = test.expected.map(|headers| {
+            headers
+                .into_iter()
+                .map(|(name, value, mode)| (name.to_string(), value.to_string(), mode))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(actual, expected, "{}", test._name);
+    }
+}
+
+#[test]
+fn test_apply_default_headers() {
+    let defaults = DefaultHeaders::parse(
+        "set User-Agent: proxyrs|append Via: 1.1 edge|default X-Org-Id: 42|default Accept: */*",
+    )
+    .unwrap();
+    let mut headers = HeaderMap::from([
+        ("Host", "example.com"),
+        ("User-Agent", "curl/8.0"),
+        ("Via", "1.0 client"),
+        ("Accept", "text/html"),
+    ]);
+
+    defaults.apply(&mut headers);
+
+    assert_eq!(
+        headers,
+        HeaderMap::from([
+            ("Host", "example.com"),
+            ("User-Agent", "proxyrs"),
+            ("Via", "1.0 client"),
+            ("Accept", "text/html"),
+            ("Via", "1.1 edge"),
+            ("X-Org-Id", "42"),
+        ])
+    );
+}
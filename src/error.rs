@@ -0,0 +1,132 @@
+use std::{error, fmt, io};
+
+use crate::status_code::StatusCode;
+
+// everything that can go wrong while proxying a request
+#[derive(Debug)]
+pub enum ProxyError {
+    // the client sent something that is not valid HTTP
+    Parse(String),
+    // the head of a message has a line or more header fields than allowed
+    HeadTooLarge(String),
+    // the upstream host name could not be resolved
+    Dns(String),
+    // no connection to the upstream could be opened
+    Connect(String),
+    // the upstream took too long
+    Timeout(String),
+    // the upstream sent something that is not valid HTTP
+    UpstreamProtocol(String),
+    Io(io::Error),
+}
+
+impl ProxyError {
+    // status the client is answered with when this error ends a request
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProxyError::Parse(_) => StatusCode::InvalidRequest,
+            ProxyError::HeadTooLarge(_) => StatusCode::RequestHeaderFieldsTooLarge,
+            ProxyError::Timeout(_) => StatusCode::GatewayTimeout,
+            ProxyError::Dns(_)
+            | ProxyError::Connect(_)
+            | ProxyError::UpstreamProtocol(_)
+            | ProxyError::Io(_) => StatusCode::BadGateway,
+        }
+    }
+
+    // the same error seen on a message read from the upstream, where
+    // anything that does not parse is the upstream's fault
+    pub fn into_upstream(self) -> Self {
+        match self {
+            ProxyError::Parse(message) | ProxyError::HeadTooLarge(message) => {
+                ProxyError::UpstreamProtocol(message)
+            }
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyError::Parse(message) => write!(f, "invalid request: {}", message),
+            ProxyError::HeadTooLarge(message) => write!(f, "head too large: {}", message),
+            ProxyError::Dns(message) => write!(f, "dns lookup failed: {}", message),
+            ProxyError::Connect(message) => write!(f, "connect failed: {}", message),
+            ProxyError::Timeout(message) => write!(f, "timed out: {}", message),
+            ProxyError::UpstreamProtocol(message) => {
+                write!(f, "invalid upstream response: {}", message)
+            }
+            ProxyError::Io(e) => write!(f, "i/o error: {}", e),
+        }
+    }
+}
+
+impl error::Error for ProxyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ProxyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// reads and writes on upstream connections fail with TimedOut once a timeout expired
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => ProxyError::Timeout(e.to_string()),
+            _ => ProxyError::Io(e),
+        }
+    }
+}
+
+#[test]
+fn test_status_code() {
+    let tests = vec![
+        (
+            ProxyError::Parse("bad".to_string()),
+            StatusCode::InvalidRequest,
+        ),
+        (
+            ProxyError::HeadTooLarge("101 header fields".to_string()),
+            StatusCode::RequestHeaderFieldsTooLarge,
+        ),
+        (
+            ProxyError::Dns("example.com".to_string()),
+            StatusCode::BadGateway,
+        ),
+        (
+            ProxyError::Connect("refused".to_string()),
+            StatusCode::BadGateway,
+        ),
+        (
+            ProxyError::Timeout("slow".to_string()),
+            StatusCode::GatewayTimeout,
+        ),
+        (
+            ProxyError::UpstreamProtocol("bad".to_string()),
+            StatusCode::BadGateway,
+        ),
+        (
+            ProxyError::Io(io::ErrorKind::ConnectionReset.into()),
+            StatusCode::BadGateway,
+        ),
+        (
+            io::Error::new(io::ErrorKind::TimedOut, "slow").into(),
+            StatusCode::GatewayTimeout,
+        ),
+        (
+            ProxyError::Parse("bad".to_string()).into_upstream(),
+            StatusCode::BadGateway,
+        ),
+        (
+            ProxyError::HeadTooLarge("long".to_string()).into_upstream(),
+            StatusCode::BadGateway,
+        ),
+    ];
+
+    for (error, expected) in tests {
+        assert_eq!(error.status_code(), expected, "{}", error);
+    }
+}
@@ -0,0 +1,218 @@
+use std::net::IpAddr;
+
+use crate::header_map::HeaderMap;
+
+// headers that only describe a single connection and are never forwarded (RFC 9110 7.6.1).
+// Transfer-Encoding is hop-by-hop as well, but bodies are forwarded with the framing they
+// arrived with, so it stays
+const HOP_BY_HOP: [&str; 7] = [
+    "Connection",
+    "Proxy-Connection",
+    "Keep-Alive",
+    "TE",
+    "Upgrade",
+    "Proxy-Authorization",
+    "Proxy-Authenticate",
+];
+
+// describe the body, whatever Connection says they are needed to read the message.
+// Content-Length is never forwarded next to Transfer-Encoding, which takes precedence
+// (RFC 9112 section 6.3), so the next hop can't find another end of the message
+const FRAMING: [&str; 2] = ["Content-Length", "Transfer-Encoding"];
+
+// which headers telling upstreams about the proxy and the client are added
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForwardingConfig {
+    // name the proxy uses for itself in Via, None to not send Via
+    pub via: Option<String>,
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    // RFC 7239
+    pub forwarded: bool,
+}
+
+impl Default for ForwardingConfig {
+    fn default() -> Self {
+        Self {
+            via: Some("proxyrs".to_string()),
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            forwarded: false,
+        }
+    }
+}
+
+// remove hop-by-hop headers, the ones the Connection header names and a
+// Content-Length that Transfer-Encoding overrides
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all("Connection")
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_string())
+        .filter(|token| {
+            !token.is_empty()
+                && !FRAMING
+                    .iter()
+                    .any(|framing| framing.eq_ignore_ascii_case(token))
+        })
+        .collect();
+    headers.retain(|name, _| {
+        !HOP_BY_HOP.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+            && !named.iter().any(|hop| hop.eq_ignore_ascii_case(name))
+    });
+    if headers.contains("Transfer-Encoding") {
+        headers.remove("Content-Length");
+    }
+}
+
+impl ForwardingConfig {
+    // headers for a request from client on its way upstream. values earlier
+    // proxies added are kept and this hop is appended to them. X-Forwarded-Proto
+    // is replaced, a client could claim https on a plain connection otherwise
+    pub fn add_request_headers(&self, headers: &mut HeaderMap, client: IpAddr, proto: &str) {
+        if let Some(via) = &self.via {
+            headers.append("Via", format!("1.1 {}", via));
+        }
+        if self.x_forwarded_for {
+            let mut forwarded_for: Vec<String> = headers
+                .get_all("X-Forwarded-For")
+                .map(str::to_string)
+                .collect();
+            forwarded_for.push(client.to_string());
+            headers.insert("X-Forwarded-For", forwarded_for.join(", "));
+        }
+        if self.x_forwarded_proto {
+            headers.insert("X-Forwarded-Proto", proto);
+        }
+        if self.forwarded {
+            let mut element = format!("for={};proto={}", forwarded_node(client), proto);
+            if let Some(host) = headers.get("Host") {
+                element.push_str(&format!(";host={}", forwarded_value(host)));
+            }
+            headers.append("Forwarded", element);
+        }
+    }
+
+    // headers for a response from upstream on its way to the client
+    pub fn add_response_headers(&self, headers: &mut HeaderMap) {
+        if let Some(via) = &self.via {
+            headers.append("Via", format!("1.1 {}", via));
+        }
+    }
+}
+
+// IPv6 addresses are bracketed and quoted, RFC 7239 6
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+// a token as is, anything else as a quoted string
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[test]
+fn test_strip_hop_by_hop() {
+    let mut headers = HeaderMap::from([
+        ("Host", "example.com"),
+        ("Connection", "close, X-Hop"),
+        ("connection", "Content-Length"),
+        ("Keep-Alive", "timeout=5"),
+        ("x-hop", "1"),
+        ("Proxy-Authorization", "Basic Zm9vOmJhcg=="),
+        ("TE", "trailers"),
+        ("Upgrade", "websocket"),
+        ("Content-Length", "5"),
+        ("Transfer-Encoding", "chunked"),
+        ("Accept", "*/*"),
+    ]);
+
+    strip_hop_by_hop(&mut headers);
+
+    assert_eq!(
+        headers,
+        HeaderMap::from([
+            ("Host", "example.com"),
+            ("Transfer-Encoding", "chunked"),
+            ("Accept", "*/*"),
+        ])
+    );
+
+    let mut headers = HeaderMap::from([("Connection", "Content-Length"), ("Content-Length", "5")]);
+    strip_hop_by_hop(&mut headers);
+    assert_eq!(headers, HeaderMap::from([("Content-Length", "5")]));
+}
+
+#[test]
+fn test_add_request_headers() {
+    let everything = ForwardingConfig {
+        forwarded: true,
+        ..ForwardingConfig::default()
+    };
+    let nothing = ForwardingConfig {
+        via: None,
+        x_forwarded_for: false,
+        x_forwarded_proto: false,
+        forwarded: false,
+    };
+    let tests = vec![
+        (
+            &everything,
+            HeaderMap::from([("Host", "example.com:8080")]),
+            "192.0.2.1",
+            HeaderMap::from([
+                ("Host", "example.com:8080"),
+                ("Via", "1.1 proxyrs"),
+                ("X-Forwarded-For", "192.0.2.1"),
+                ("X-Forwarded-Proto", "http"),
+                (
+                    "Forwarded",
+                    "for=192.0.2.1;proto=http;host=\"example.com:8080\"",
+                ),
+            ]),
+        ),
+        (
+            &everything,
+            HeaderMap::from([
+                ("Host", "example.com"),
+                ("X-Forwarded-For", "198.51.100.7"),
+                ("X-Forwarded-Proto", "https"),
+                ("Via", "1.0 edge"),
+            ]),
+            "2001:db8::1",
+            HeaderMap::from([
+                ("Host", "example.com"),
+                ("X-Forwarded-For", "198.51.100.7, 2001:db8::1"),
+                ("X-Forwarded-Proto", "http"),
+                ("Via", "1.0 edge"),
+                ("Via", "1.1 proxyrs"),
+                (
+                    "Forwarded",
+                    "for=\"[2001:db8::1]\";proto=http;host=example.com",
+                ),
+            ]),
+        ),
+        (
+            &nothing,
+            HeaderMap::from([("Host", "example.com")]),
+            "192.0.2.1",
+            HeaderMap::from([("Host", "example.com")]),
+        ),
+    ];
+
+    for (config, mut headers, client, expected) in tests {
+        config.add_request_headers(&mut headers, client.parse().unwrap(), "http");
+        assert_eq!(headers, expected);
+    }
+}
@@ -0,0 +1,171 @@
+use std::ops::Index;
+
+// header fields of a message in the order they were received or added.
+// names keep their original casing but are looked up case-insensitively,
+// a name can appear more than once (e.g. Set-Cookie)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // first value of name
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // every value of name in order
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    // add a value after any existing ones
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    // replace every value of name. the field keeps the position of its first
+    // occurrence, or goes last if it was not present
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let value = value.into();
+        match self
+            .entries
+            .iter()
+            .position(|(key, _)| key.eq_ignore_ascii_case(&name))
+        {
+            Some(position) => {
+                let mut index = 0;
+                self.entries.retain(|(key, _)| {
+                    let keep = index <= position || !key.eq_ignore_ascii_case(&name);
+                    index += 1;
+                    keep
+                });
+                self.entries[position] = (name, value);
+            }
+            None => self.entries.push((name, value)),
+        }
+    }
+
+    // add name only if it has no value yet
+    pub fn insert_if_absent(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        if !self.contains(&name) {
+            self.entries.push((name, value.into()));
+        }
+    }
+
+    // remove every value of name, true if there was one
+    pub fn remove(&mut self, name: &str) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.entries.len() != len
+    }
+
+    pub fn retain<F: FnMut(&str, &str) -> bool>(&mut self, mut keep: F) {
+        self.entries.retain(|(key, value)| keep(key, value));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    // number of fields, counting every value of a repeated name
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl Index<&str> for HeaderMap {
+    type Output = str;
+
+    fn index(&self, name: &str) -> &str {
+        self.get(name)
+            .unwrap_or_else(|| panic!("missing header {:?}", name))
+    }
+}
+
+impl<K: Into<String>, V: Into<String>> FromIterator<(K, V)> for HeaderMap {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut headers = HeaderMap::new();
+        for (name, value) in iter {
+            headers.append(name, value);
+        }
+        headers
+    }
+}
+
+impl<K: Into<String>, V: Into<String>, const N: usize> From<[(K, V); N]> for HeaderMap {
+    fn from(entries: [(K, V); N]) -> Self {
+        entries.into_iter().collect()
+    }
+}
+
+#[test]
+fn test_header_map_lookup() {
+    let headers = HeaderMap::from([
+        ("Content-Type", "text/html"),
+        ("Set-Cookie", "a=1"),
+        ("set-cookie", "b=2"),
+        ("X-Custom-ID", "42"),
+    ]);
+
+    assert_eq!(headers.get("content-type"), Some("text/html"));
+    assert_eq!(headers.get("CONTENT-TYPE"), Some("text/html"));
+    assert_eq!(headers["x-custom-id"], *"42");
+    assert_eq!(headers.get("Content-Length"), None);
+    assert_eq!(
+        headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+        vec!["a=1", "b=2"]
+    );
+    assert_eq!(headers.len(), 4);
+}
+
+#[test]
+fn test_header_map_keeps_order_and_casing() {
+    let mut headers = HeaderMap::new();
+    headers.append("Host", "example.com");
+    headers.append("x-lower", "1");
+    headers.append("Accept", "*/*");
+    headers.append("X-LOWER", "2");
+
+    // insert replaces every value in place of the first one
+    headers.insert("X-Lower", "3");
+    headers.insert_if_absent("Host", "other.example.com");
+    headers.insert_if_absent("User-Agent", "curl");
+
+    assert_eq!(
+        headers.iter().collect::<Vec<_>>(),
+        vec![
+            ("Host", "example.com"),
+            ("X-Lower", "3"),
+            ("Accept", "*/*"),
+            ("User-Agent", "curl"),
+        ]
+    );
+
+    assert!(headers.remove("accept"));
+    assert!(!headers.remove("accept"));
+    assert_eq!(headers.len(), 3);
+}
@@ -0,0 +1,284 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{task::JoinHandle, time::MissedTickBehavior};
+
+use crate::{
+    balancer::Balancer,
+    default_headers::DefaultHeaders,
+    header_map::HeaderMap,
+    http_client::{ClientConfig, HTTPClient},
+    http_method::Method,
+    http_request::HttpRequest,
+    http_version::Version,
+    retry::RetryPolicy,
+    routing::RoutingTable,
+    timeout::Timeouts,
+    utils::parse_url,
+};
+
+// how the endpoints of a cluster are probed. a probe passes if the endpoint answers
+// GET path with a 2xx status within timeout
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub path: String,
+    pub interval: Duration,
+    pub timeout: Duration,
+    // passed probes in a row that bring an unhealthy endpoint back into rotation
+    pub healthy_threshold: usize,
+    // failed probes in a row that take a healthy endpoint out of rotation
+    pub unhealthy_threshold: usize,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: "/healthz".to_string(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+// counts probe results in a row for one endpoint
+#[derive(Debug, Default)]
+struct Streak {
+    passed: usize,
+    failed: usize,
+}
+
+impl Streak {
+    // the state the endpoint should be in, None while it should stay as it is
+    fn record(&mut self, passed: bool, check: &HealthCheck) -> Option<bool> {
+        if passed {
+            self.passed += 1;
+            self.failed = 0;
+            (self.passed >= check.healthy_threshold).then_some(true)
+        } else {
+            self.failed += 1;
+            self.passed = 0;
+            (self.failed >= check.unhealthy_threshold).then_some(false)
+        }
+    }
+}
+
+// starts probing every endpoint of every cluster that has a health check. the
+// probes run until their handles are aborted
+pub fn spawn_health_checks(routing: &RoutingTable) -> Vec<JoinHandle<()>> {
+    // probes are never retried, a failed probe is a result
+    let client = Arc::new(HTTPClient::with_config(
+        DefaultHeaders::new(),
+        ClientConfig {
+            retry: RetryPolicy {
+                max_attempts: 1,
+                ..RetryPolicy::default()
+            },
+            ..ClientConfig::default()
+        },
+    ));
+    let mut handles = Vec::new();
+    for cluster in routing.clusters() {
+        let check = match &cluster.health_check {
+            Some(check) => check,
+            None => continue,
+        };
+        for endpoint in 0..cluster.balancer.endpoints().len() {
+            handles.push(tokio::spawn(check_endpoint(
+                Arc::clone(&client),
+                cluster.name.clone(),
+                Arc::clone(&cluster.balancer),
+                endpoint,
+                check.clone(),
+            )));
+        }
+    }
+    handles
+}
+
+async fn check_endpoint(
+    client: Arc<HTTPClient>,
+    cluster: String,
+    balancer: Arc<Balancer>,
+    endpoint: usize,
+    check: HealthCheck,
+) {
+    let endpoint = &balancer.endpoints()[endpoint];
+    let mut streak = Streak::default();
+    let mut interval = tokio::time::interval(check.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let passed = match probe(&client, &endpoint.address, &check).await {
+            Ok(()) => true,
+            Err(e) => {
+                log::debug!(
+                    "health check of {} in cluster {} failed: {}",
+                    endpoint.address,
+                    cluster,
+                    e
+                );
+                false
+            }
+        };
+        let healthy = match streak.record(passed, &check) {
+            Some(healthy) => healthy,
+            None => continue,
+        };
+        if endpoint.set_healthy(healthy) {
+            if healthy {
+                log::info!(
+                    "endpoint {} in cluster {} is healthy again",
+                    endpoint.address,
+                    cluster
+                );
+            } else {
+                log::warn!(
+                    "endpoint {} in cluster {} is unhealthy, taking it out of rotation",
+                    endpoint.address,
+                    cluster
+                );
+            }
+        }
+    }
+}
+
+async fn probe(client: &HTTPClient, address: &str, check: &HealthCheck) -> Result<(), String> {
+    let request = HttpRequest {
+        method: Method::Get,
+        url: parse_url(&format!("http://{}{}", address, check.path)).map_err(|e| e.to_string())?,
+        target: None,
+        version: Version::Http11,
+        headers: HeaderMap::from([
+            ("Host".to_string(), address.to_string()),
+            ("User-Agent".to_string(), "proxyrs-health-check".to_string()),
+        ]),
+        body: Vec::new(),
+    };
+    let timeouts = Timeouts {
+        connect: check.timeout,
+        first_byte: check.timeout,
+        idle_read: check.timeout,
+        total: check.timeout,
+    };
+    let response = client
+        .execute_with_timeouts_async(request, &timeouts)
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status_code.is_success() {
+        Ok(())
+    } else {
+        Err(format!("status {}", response.status_code.to_u32()))
+    }
+}
+
+#[test]
+fn test_streak() {
+    let check = HealthCheck {
+        healthy_threshold: 2,
+        unhealthy_threshold: 3,
+        ..HealthCheck::default()
+    };
+    let mut streak = Streak::default();
+    let results: Vec<Option<bool>> = [false, false, true, false, false, false, false, true, true]
+        .iter()
+        .map(|passed| streak.record(*passed, &check))
+        .collect();
+    assert_eq!(
+        results,
+        vec![
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(false),
+            Some(false),
+            None,
+            Some(true)
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_health_checks_take_endpoints_out_of_rotation() {
+    use crate::{
+        balancer::{Endpoint, Policy},
+        routing::{Cluster, PathMatch, Route},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    // answers every probe with the status it is told to, one probe per connection
+    let (status_sender, status) = tokio::sync::watch::channel(200);
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = upstream.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = socket.read(&mut buffer).await;
+            let response = format!(
+                "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                *status.borrow()
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let cluster = Cluster::new(
+        "users",
+        Policy::RoundRobin,
+        vec![
+            Endpoint::new(upstream_address, 1),
+            // nothing listens here
+            Endpoint::new("127.0.0.1:1", 1),
+        ],
+    )
+    .unwrap()
+    .with_health_check(HealthCheck {
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_millis(200),
+        healthy_threshold: 1,
+        unhealthy_threshold: 2,
+        ..HealthCheck::default()
+    });
+    let routing = RoutingTable::new(
+        vec![Route {
+            host: None,
+            path: PathMatch::Any,
+            methods: vec![],
+            cluster: "users".to_string(),
+            rewrite: None,
+        }],
+        vec![cluster.clone()],
+    )
+    .unwrap();
+    let handles = spawn_health_checks(&routing);
+    assert_eq!(handles.len(), 2);
+
+    let endpoints = cluster.balancer.endpoints();
+    let wait_for = |up: bool, down: bool| async move {
+        for _ in 0..100 {
+            if endpoints[0].is_healthy() == up && endpoints[1].is_healthy() == down {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    };
+    assert!(wait_for(true, false).await);
+
+    status_sender.send_replace(503);
+    assert!(wait_for(false, false).await);
+    status_sender.send_replace(200);
+    assert!(wait_for(true, false).await);
+    assert_eq!(endpoints[0].health_changes(), 2);
+
+    for handle in handles {
+        handle.abort();
+    }
+}
@@ -1,56 +1,804 @@
-use std::{
-    collections::HashMap,
-    net::{SocketAddr, TcpStream},
+use std::{sync::OnceLock, time::Duration};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, BufReader},
+    net::TcpStream,
+    runtime::Runtime,
+    time::Instant,
 };
 
 use crate::{
+    body::{copy_body, BodyReader},
+    connection_pool::{ConnectionPool, PoolConfig, PoolStats},
+    default_headers::DefaultHeaders,
+    error::ProxyError,
     http_request::HttpRequest,
     http_response::HttpResponse,
-    utils::{nslookup, write_to_stream},
+    retry::{RetryBudget, RetryPolicy},
+    timeout::{with_deadline, TimeoutStream, Timeouts},
+    utils::{
+        body_framing, connect_any_async, parse_authority, read_response_head_async,
+        write_to_stream_async, BodyFraming,
+    },
 };
 
+// settings for how HTTPClient talks to upstreams
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfig {
+    pub pool: PoolConfig,
+    // used for every request not sent with its own timeouts
+    pub timeouts: Timeouts,
+    pub retry: RetryPolicy,
+}
+
 pub struct HTTPClient {
-    pub default_headers: HashMap<String, String>,
+    // merged into every request before it is sent
+    pub default_headers: DefaultHeaders,
+    pool: ConnectionPool,
+    timeouts: Timeouts,
+    retry: RetryPolicy,
+    retry_budget: RetryBudget,
+    // drives the blocking facade. it keeps its own worker thread so pooled
+    // connections stay usable between blocking calls
+    blocking_runtime: OnceLock<Runtime>,
 }
 
 impl HTTPClient {
-    pub fn new(default_headers: HashMap<String, String>) -> Self {
-        Self { default_headers }
+    pub fn new(default_headers: impl Into<DefaultHeaders>) -> Self {
+        Self::with_config(default_headers, ClientConfig::default())
+    }
+
+    pub fn with_config(default_headers: impl Into<DefaultHeaders>, config: ClientConfig) -> Self {
+        Self {
+            default_headers: default_headers.into(),
+            pool: ConnectionPool::new(config.pool),
+            timeouts: config.timeouts,
+            retry_budget: RetryBudget::new(&config.retry),
+            retry: config.retry,
+            blocking_runtime: OnceLock::new(),
+        }
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    pub fn timeouts(&self) -> &Timeouts {
+        &self.timeouts
+    }
+
+    // blocking facade over execute_async for callers without a runtime.
+    // must not be called from inside a tokio runtime
+    pub fn execute(&self, request: HttpRequest) -> Result<HttpResponse, ProxyError> {
+        let runtime = match self.blocking_runtime.get() {
+            Some(runtime) => runtime,
+            None => {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(1)
+                    .enable_all()
+                    .build()?;
+                self.blocking_runtime.get_or_init(|| runtime)
+            }
+        };
+        runtime.block_on(self.execute_async(request))
     }
 
-    pub fn execute(
+    // send request and buffer the whole response
+    pub async fn execute_async(&self, request: HttpRequest) -> Result<HttpResponse, ProxyError> {
+        self.execute_with_timeouts_async(request, &self.timeouts)
+            .await
+    }
+
+    // execute_async with timeouts other than the client's
+    pub async fn execute_with_timeouts_async(
         &self,
         request: HttpRequest,
-    ) -> Result<HttpResponse, Box<dyn std::error::Error>> {
-        let ip_address = nslookup(
+        timeouts: &Timeouts,
+    ) -> Result<HttpResponse, ProxyError> {
+        // the body is in memory, every attempt can send it again
+        let framing = BodyFraming::ContentLength(request.body.len());
+        let mut body = BodyReader::new(request.body.as_slice(), framing);
+        self.send_with_retries_async(
+            &request,
+            &mut body,
+            timeouts,
+            |body| {
+                *body = BodyReader::new(request.body.as_slice(), framing);
+                true
+            },
+            || None,
+        )
+        .await?
+        .into_response()
+        .await
+    }
+
+    // send the head of request followed by body, which is streamed to the upstream
+    // as it is read. the response body is left on the connection for the caller to stream.
+    // reading the response body keeps failing with ProxyError::Timeout once timeouts expire
+    pub async fn send_async<R: AsyncBufRead + Unpin>(
+        &self,
+        request: &HttpRequest,
+        body: &mut BodyReader<R>,
+        timeouts: &Timeouts,
+    ) -> Result<ResponseStream<'_>, ProxyError> {
+        self.send_rerouting_async(request, body, timeouts, || None)
+            .await
+    }
+
+    // send_async that asks reroute for the authority of another upstream whenever
+    // one could not be connected, None tries the same one again
+    pub async fn send_rerouting_async<R, P>(
+        &self,
+        request: &HttpRequest,
+        body: &mut BodyReader<R>,
+        timeouts: &Timeouts,
+        reroute: P,
+    ) -> Result<ResponseStream<'_>, ProxyError>
+    where
+        R: AsyncBufRead + Unpin,
+        P: FnMut() -> Option<String>,
+    {
+        // a streamed body is gone once sent, unless there was none
+        let empty = body.is_done();
+        self.send_with_retries_async(request, body, timeouts, |_| empty, reroute)
+            .await
+    }
+
+    // sends request until an answer worth keeping arrives or the retry policy gives up.
+    // a request that could not be connected is always tried again, one the upstream
+    // answered with a retryable status only if its method is idempotent and rewind
+    // could prepare body to be sent once more. the retry after a connect error goes
+    // to the upstream reroute names, if it names one
+    async fn send_with_retries_async<R, F, P>(
+        &self,
+        request: &HttpRequest,
+        body: &mut BodyReader<R>,
+        timeouts: &Timeouts,
+        mut rewind: F,
+        mut reroute: P,
+    ) -> Result<ResponseStream<'_>, ProxyError>
+    where
+        R: AsyncBufRead + Unpin,
+        F: FnMut(&mut BodyReader<R>) -> bool,
+        P: FnMut() -> Option<String>,
+    {
+        let deadline = Instant::now() + timeouts.total;
+        let mut host = request
+            .url
+            .host_str()
+            .ok_or_else(|| ProxyError::Parse(format!("no host in {}", request.url)))?
+            .to_string();
+        let mut port = request.url.port().unwrap_or(80);
+        let mut route = format!("{}:{}", host, port);
+        self.retry_budget.record_request(&route);
+
+        // the body is sent from body, only the head needs the default headers
+        let with_defaults;
+        let request = if self.default_headers.is_empty() {
             request
-                .url
-                .host_str()
-                .expect("failed to get url host: {}")
-                .to_string(),
+        } else {
+            let mut headers = request.headers.clone();
+            self.default_headers.apply(&mut headers);
+            with_defaults = HttpRequest {
+                method: request.method.clone(),
+                url: request.url.clone(),
+                target: request.target.clone(),
+                version: request.version,
+                headers,
+                body: Vec::new(),
+            };
+            &with_defaults
+        };
+
+        let mut attempt = 1;
+        loop {
+            let result = self
+                .send_once_async(request, body, &host, port, timeouts, deadline, &mut rewind)
+                .await;
+            let retryable = match &result {
+                Ok(response) => {
+                    self.retry
+                        .is_retryable_status(response.response.status_code)
+                        && request.method.is_idempotent()
+                        && rewind(body)
+                }
+                // a connect error means nothing was sent, body is still untouched
+                Err(e) => self.retry.is_retryable_error(e),
+            };
+            if !retryable {
+                return result;
+            }
+
+            let backoff = match self.retry_backoff(&route, attempt, deadline) {
+                Some(backoff) => backoff,
+                None => return result,
+            };
+            match &result {
+                Ok(response) => log::warn!(
+                    "retrying {} {} after upstream answered {}",
+                    request.method,
+                    request.url,
+                    response.response.status_code.to_u32()
+                ),
+                Err(e) => {
+                    log::warn!("retrying {} {} after {}", request.method, request.url, e);
+                    // further retries are charged to the upstream they are sent to
+                    let next = match e {
+                        ProxyError::Connect(_) => reroute(),
+                        _ => None,
+                    };
+                    if let Some((next_host, next_port)) =
+                        next.and_then(|authority| parse_authority(&authority).ok())
+                    {
+                        log::info!("sending the retry to {}:{}", next_host, next_port);
+                        (host, port) = (next_host, next_port);
+                        route = format!("{}:{}", host, port);
+                    }
+                }
+            }
+            // a response that is retried is not read, its connection is closed
+            drop(result);
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    // how long to wait before retrying after attempt, None if there is no retry left
+    // for the route or it would not finish before deadline
+    fn retry_backoff(&self, route: &str, attempt: usize, deadline: Instant) -> Option<Duration> {
+        if attempt >= self.retry.max_attempts {
+            return None;
+        }
+        let backoff = self.retry.backoff(attempt);
+        if Instant::now() + backoff >= deadline {
+            return None;
+        }
+        if !self.retry_budget.try_retry(route) {
+            log::warn!("retry budget for {} used up, not retrying", route);
+            return None;
+        }
+        Some(backoff)
+    }
+
+    // a new connection to host:port. running out of the connect timeout is a connect
+    // error like any other, nothing was sent yet. running out of deadline stays a timeout
+    async fn connect_async(
+        &self,
+        host: &str,
+        port: u16,
+        timeouts: &Timeouts,
+        deadline: Instant,
+    ) -> Result<TcpStream, ProxyError> {
+        let what = format!("connecting to {}:{}", host, port);
+        with_deadline(
+            connect_any_async(host, port),
+            timeouts.connect,
+            deadline,
+            &what,
         )
-        .expect("failed to resolve domain name to ip address");
+        .await
+        .map_err(|e| match e {
+            ProxyError::Timeout(message) if Instant::now() < deadline => {
+                ProxyError::Connect(message)
+            }
+            e => e,
+        })
+    }
+
+    // sends request on an idle pooled connection to host:port, or a new one.
+    // the upstream may have closed a pooled connection while it sat idle, which shows
+    // as the connection closing before the response starts. the request then goes out
+    // once more on a new connection, if rewind can prepare body for it. a connection
+    // that failed could have broken after the upstream acted on the request, so only
+    // idempotent requests are sent again after an error
+    #[allow(clippy::too_many_arguments)]
+    async fn send_once_async<R, F>(
+        &self,
+        request: &HttpRequest,
+        body: &mut BodyReader<R>,
+        host: &str,
+        port: u16,
+        timeouts: &Timeouts,
+        deadline: Instant,
+        rewind: &mut F,
+    ) -> Result<ResponseStream<'_>, ProxyError>
+    where
+        R: AsyncBufRead + Unpin,
+        F: FnMut(&mut BodyReader<R>) -> bool,
+    {
+        let mut pooled = self.pool.checkout(host, port);
+        loop {
+            let reused = pooled.is_some();
+            let stream = match pooled.take() {
+                Some(stream) => stream,
+                None => self.connect_async(host, port, timeouts, deadline).await?,
+            };
+            let mut reader = BufReader::new(TimeoutStream::new(stream, timeouts, deadline));
+            match send_request_async(&mut reader, request, body).await {
+                Ok(true) => return self.read_response_async(reader, request, host, port).await,
+                Ok(false) if reused && rewind(body) => {
+                    log::debug!(
+                        "pooled connection to {}:{} was closed, sending on a new one",
+                        host,
+                        port
+                    );
+                }
+                Err(ProxyError::Io(e))
+                    if reused && request.method.is_idempotent() && rewind(body) =>
+                {
+                    log::debug!(
+                        "pooled connection to {}:{} failed with {}, sending on a new one",
+                        host,
+                        port,
+                        e
+                    );
+                }
+                Ok(false) => {
+                    return Err(ProxyError::UpstreamProtocol(format!(
+                        "{}:{} closed the connection without a response",
+                        host, port
+                    )))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
 
-        let port = request.url.port().unwrap_or(80);
+    async fn read_response_async(
+        &self,
+        mut reader: BufReader<TimeoutStream<TcpStream>>,
+        request: &HttpRequest,
+        host: &str,
+        port: u16,
+    ) -> Result<ResponseStream<'_>, ProxyError> {
+        let (response, framing) = read_response_head_async(&mut reader, &request.method)
+            .await
+            .map_err(ProxyError::into_upstream)?;
+        // a body delimited by closing the connection leaves nothing to reuse
+        let reusable = response.keep_alive() && framing != BodyFraming::CloseDelimited;
+        Ok(ResponseStream {
+            response,
+            body: BodyReader::new(reader, framing),
+            framing,
+            client: self,
+            host: host.to_string(),
+            port,
+            reusable,
+        })
+    }
+}
 
-        let socket_address = SocketAddr::new(ip_address, port);
-        let mut stream = TcpStream::connect(socket_address).expect("failed to connect to server");
+// writes request and its body to the upstream and waits for the response to start.
+// false if the upstream closed the connection instead
+async fn send_request_async<R: AsyncBufRead + Unpin>(
+    stream: &mut BufReader<TimeoutStream<TcpStream>>,
+    request: &HttpRequest,
+    body: &mut BodyReader<R>,
+) -> Result<bool, ProxyError> {
+    write_to_stream_async(stream, &request.serialize_head()).await?;
+    let chunked = body_framing(&request.headers)? == BodyFraming::Chunked;
+    copy_body(body, stream, chunked).await?;
+    Ok(!stream.fill_buf().await?.is_empty())
+}
 
-        let request_string = request.serialize();
-        write_to_stream(&mut stream, &request_string).expect("failed to write to socket");
-        let response = HttpResponse::from_stream(&mut stream).expect("failed to read from socket");
+// an upstream response whose body has not been read yet
+pub struct ResponseStream<'a> {
+    // status and headers, the body is empty
+    pub response: HttpResponse,
+    pub body: BodyReader<BufReader<TimeoutStream<TcpStream>>>,
+    // how the upstream delimits the body
+    pub framing: BodyFraming,
+    client: &'a HTTPClient,
+    host: String,
+    port: u16,
+    reusable: bool,
+}
+
+impl ResponseStream<'_> {
+    // buffer the rest of the body into the response
+    pub async fn into_response(mut self) -> Result<HttpResponse, ProxyError> {
+        let body = self
+            .body
+            .read_to_end()
+            .await
+            .map_err(ProxyError::into_upstream)?;
+        let trailers = self.body.trailers().clone();
+        let mut response = self.finish();
+        response.body = body;
+        for (key, value) in trailers.iter() {
+            response.headers.insert_if_absent(key, value);
+        }
         Ok(response)
     }
+
+    // done with the response. the connection goes back to the pool if the body was
+    // read to the end and nothing follows it, otherwise it is closed
+    pub fn finish(self) -> HttpResponse {
+        if self.reusable && self.body.is_done() {
+            let reader = self.body.into_inner();
+            if reader.buffer().is_empty() {
+                self.client
+                    .pool
+                    .checkin(&self.host, self.port, reader.into_inner().into_inner());
+            }
+        }
+        self.response
+    }
 }
 
 // test for proxy request
 #[test]
 fn test_proxy_request() {
-    let mut dummy_request =
-        "GET http://google.com HTTP/1.1\r\nHost: http://google.com\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n".as_bytes();
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    // local upstream that answers every request with 400
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        let (mut socket, _) = upstream.accept().unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).unwrap();
+        socket
+            .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+    });
+
+    let raw_request = format!(
+        "GET http://localhost:{}/ HTTP/1.1\r\nHost: localhost:{}\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n",
+        port, port
+    );
+    let mut dummy_request = raw_request.as_bytes();
 
     let request = HttpRequest::from_stream(&mut dummy_request).unwrap();
-    let client = HTTPClient::new(HashMap::new());
+    let client = HTTPClient::new(DefaultHeaders::new());
     let response = client.execute(request).unwrap();
     assert_eq!(response.status_code.to_u32(), 400);
 }
+
+#[tokio::test]
+async fn test_proxy_request_async() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+    });
+
+    let raw_request = format!(
+        "GET http://localhost:{}/ HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+        port, port
+    );
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+    let client = HTTPClient::new(DefaultHeaders::new());
+    let response = client.execute_async(request).await.unwrap();
+    assert_eq!(response.status_code.to_u32(), 200);
+    assert_eq!(response.body, b"hello");
+}
+
+#[tokio::test]
+async fn test_execute_reuses_upstream_connection() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    // upstream accepts a single connection and answers every request on it
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (socket, _) = upstream.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        loop {
+            let mut line = String::new();
+            if socket.read_line(&mut line).await.unwrap() == 0 {
+                break;
+            }
+            if line == "\r\n" {
+                socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+                    .await
+                    .unwrap();
+            }
+        }
+    });
+
+    let client = HTTPClient::new(DefaultHeaders::new());
+    for _ in 0..3 {
+        let raw_request = format!(
+            "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+            port, port
+        );
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let response = client.execute_async(request).await.unwrap();
+        assert_eq!(response.body, b"OK");
+    }
+    assert_eq!(
+        client.pool_stats(),
+        PoolStats {
+            hits: 2,
+            misses: 1,
+            evictions: 0
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_resends_request_closed_on_pooled_connection() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    // the first connection answers one request and is closed as the second one
+    // arrives, like an upstream closing a connection it thinks is idle
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        for body in ["first", "fresh"] {
+            let (socket, _) = upstream.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut line = String::new();
+            while socket.read_line(&mut line).await.unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            line.clear();
+            let _ = socket.read_line(&mut line).await;
+        }
+    });
+
+    let client = HTTPClient::new(DefaultHeaders::new());
+    for expected in ["first", "fresh"] {
+        let raw_request = format!(
+            "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+            port, port
+        );
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let response = client.execute_async(request).await.unwrap();
+        assert_eq!(response.body, expected.as_bytes());
+    }
+    assert_eq!(client.pool_stats().hits, 1);
+}
+
+#[tokio::test]
+async fn test_does_not_resend_post_after_reset() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+    // answers the first request and resets the connection once it read the second,
+    // which the upstream may already have acted on
+    let requests = Arc::new(AtomicUsize::new(0));
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    let received = Arc::clone(&requests);
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = upstream.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            loop {
+                let mut line = String::new();
+                while socket.read_line(&mut line).await.unwrap() > 0 && line != "\r\n" {
+                    line.clear();
+                }
+                if line.is_empty() {
+                    break;
+                }
+                let mut body = [0; 4];
+                socket.read_exact(&mut body).await.unwrap();
+                if received.fetch_add(1, Ordering::SeqCst) == 0 {
+                    socket
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+                        .await
+                        .unwrap();
+                } else {
+                    socket.get_ref().set_linger(Some(Duration::ZERO)).unwrap();
+                    break;
+                }
+            }
+        }
+    });
+
+    let client = HTTPClient::new(DefaultHeaders::new());
+    let raw_request = format!(
+        "POST http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nContent-Length: 4\r\n\r\nbody",
+        port, port
+    );
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+    let response = client.execute_async(request.clone()).await.unwrap();
+    assert_eq!(response.body, b"OK");
+    assert!(client.execute_async(request).await.is_err());
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert_eq!(client.pool_stats().hits, 1);
+}
+
+#[test]
+fn test_blocking_execute_repeatedly() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    // every blocking call runs on its own runtime, connections pooled by an
+    // earlier call must not break later ones
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for socket in upstream.incoming() {
+            let mut socket = BufReader::new(socket.unwrap());
+            let mut line = String::new();
+            while socket.read_line(&mut line).unwrap() > 0 {
+                if line == "\r\n" {
+                    socket
+                        .get_mut()
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nOK")
+                        .unwrap();
+                }
+                line.clear();
+            }
+        }
+    });
+
+    let client = HTTPClient::new(DefaultHeaders::new());
+    for _ in 0..3 {
+        let raw_request = format!(
+            "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+            port, port
+        );
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let response = client.execute(request).unwrap();
+        assert_eq!(response.body, b"OK");
+    }
+}
+
+#[tokio::test]
+async fn test_retries_idempotent_requests() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    // upstream that is unavailable for the first request it sees, then answers 200
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let mut seen = 0;
+        loop {
+            let (socket, _) = upstream.accept().await.unwrap();
+            let mut socket = BufReader::new(socket);
+            let mut line = String::new();
+            while socket.read_line(&mut line).await.unwrap() > 0 && line != "\r\n" {
+                line.clear();
+            }
+            seen += 1;
+            let response: &[u8] = if seen == 1 || seen == 3 {
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            } else {
+                b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nOK"
+            };
+            socket.write_all(response).await.unwrap();
+        }
+    });
+
+    let client = HTTPClient::with_config(
+        DefaultHeaders::new(),
+        ClientConfig {
+            retry: RetryPolicy {
+                base_backoff: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            },
+            ..ClientConfig::default()
+        },
+    );
+    let tests = vec![("GET", 200), ("POST", 503)];
+
+    for (method, expected) in tests {
+        let raw_request = format!(
+            "{} http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+            method, port, port
+        );
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let response = client.execute_async(request).await.unwrap();
+        assert_eq!(response.status_code.to_u32(), expected, "{}", method);
+    }
+}
+
+#[tokio::test]
+async fn test_retries_after_connect_timeout() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    let (unresponsive, _queued) = crate::utils::unresponsive_listener().await;
+    let unresponsive_port = unresponsive.local_addr().unwrap().port();
+
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (socket, _) = upstream.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        let mut line = String::new();
+        while socket.read_line(&mut line).await.unwrap() > 0 && line != "\r\n" {
+            line.clear();
+        }
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let client = HTTPClient::with_config(
+        DefaultHeaders::new(),
+        ClientConfig {
+            retry: RetryPolicy {
+                base_backoff: Duration::from_millis(1),
+                ..RetryPolicy::default()
+            },
+            ..ClientConfig::default()
+        },
+    );
+    let timeouts = Timeouts {
+        connect: Duration::from_millis(100),
+        ..Timeouts::default()
+    };
+
+    // the first attempt times out connecting, so even a POST is sent again
+    let raw_request = format!(
+        "POST http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 0\r\n\r\n",
+        unresponsive_port
+    );
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+    let mut body = BodyReader::new(&b""[..], BodyFraming::ContentLength(0));
+    let response = client
+        .send_rerouting_async(&request, &mut body, &timeouts, || {
+            Some(format!("127.0.0.1:{}", port))
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.response.status_code.to_u32(), 200);
+}
+
+#[tokio::test]
+async fn test_default_headers_are_sent() {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    // upstream that answers with the head of the request it got
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (socket, _) = upstream.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            socket.read_line(&mut head).await.unwrap();
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+            head.len(),
+            head
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+    });
+
+    let client = HTTPClient::new(
+        DefaultHeaders::parse("set User-Agent: proxyrs|default X-Org-Id: 42").unwrap(),
+    );
+    let raw_request = format!(
+        "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nUser-Agent: curl/8.0\r\n\r\n",
+        port, port
+    );
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+    let response = client.execute_async(request).await.unwrap();
+    assert_eq!(
+        response.body_text().unwrap(),
+        format!(
+            "GET / HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nUser-Agent: proxyrs\r\nX-Org-Id: 42\r\n\r\n",
+            port
+        )
+    );
+}
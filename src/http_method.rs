@@ -2,6 +2,8 @@ use std::{fmt, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
+use crate::error::ProxyError;
+
 // enum for methods implements Display trait
 #[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub enum Method {
@@ -14,6 +16,8 @@ pub enum Method {
     Trace,
     Connect,
     Patch,
+    // any other method that is a valid token, e.g. PROPFIND or PURGE
+    Extension(String),
 }
 
 impl fmt::Display for Method {
@@ -31,13 +35,14 @@ impl fmt::Display for Method {
                 Method::Trace => "TRACE",
                 Method::Connect => "CONNECT",
                 Method::Patch => "PATCH",
+                Method::Extension(method) => method,
             }
         )
     }
 }
 
 impl FromStr for Method {
-    type Err = Box<dyn std::error::Error>;
+    type Err = ProxyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
@@ -50,7 +55,132 @@ impl FromStr for Method {
             "TRACE" => Ok(Method::Trace),
             "CONNECT" => Ok(Method::Connect),
             "PATCH" => Ok(Method::Patch),
-            _ => Err("invalid method".into()),
+            _ if is_token(s) => Ok(Method::Extension(s.to_string())),
+            _ => Err(ProxyError::Parse(format!("invalid method {:?}", s))),
+        }
+    }
+}
+
+// registered extension methods (https://www.iana.org/assignments/http-methods)
+// that are safe, every one of them is also idempotent
+const SAFE_EXTENSIONS: [&str; 3] = ["PROPFIND", "REPORT", "SEARCH"];
+
+// registered extension methods that are idempotent but not safe
+const IDEMPOTENT_EXTENSIONS: [&str; 25] = [
+    "ACL",
+    "BASELINE-CONTROL",
+    "BIND",
+    "CHECKIN",
+    "CHECKOUT",
+    "COPY",
+    "LABEL",
+    "LINK",
+    "MERGE",
+    "MKACTIVITY",
+    "MKCALENDAR",
+    "MKCOL",
+    "MKREDIRECTREF",
+    "MKWORKSPACE",
+    "MOVE",
+    "ORDERPATCH",
+    "PROPPATCH",
+    "REBIND",
+    "UNBIND",
+    "UNCHECKOUT",
+    "UNLINK",
+    "UNLOCK",
+    "UPDATE",
+    "UPDATEREDIRECTREF",
+    "VERSION-CONTROL",
+];
+
+impl Method {
+    // the client does not ask for any state change on the server (RFC 9110 section 9.2.1)
+    pub fn is_safe(&self) -> bool {
+        match self {
+            Method::Get | Method::Head | Method::Options | Method::Trace => true,
+            Method::Extension(method) => SAFE_EXTENSIONS.contains(&method.as_str()),
+            _ => false,
+        }
+    }
+
+    // sending the request twice has the same effect as sending it once, so it can be
+    // retried after a failure (RFC 9110 section 9.2.2). unknown methods are assumed not to be
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Method::Put | Method::Delete => true,
+            Method::Extension(method) => {
+                self.is_safe() || IDEMPOTENT_EXTENSIONS.contains(&method.as_str())
+            }
+            _ => self.is_safe(),
+        }
+    }
+
+    // responses may be stored by a cache (RFC 9110 section 9.2.3). POST is left out,
+    // its responses can only be reused with explicit freshness information
+    pub fn is_cacheable(&self) -> bool {
+        matches!(self, Method::Get | Method::Head)
+    }
+}
+
+// token = 1*tchar (RFC 9110 section 5.6.2)
+pub fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[test]
+fn test_from_str() {
+    let tests = vec![
+        ("GET", Some(Method::Get)),
+        ("PATCH", Some(Method::Patch)),
+        ("PROPFIND", Some(Method::Extension("PROPFIND".to_string()))),
+        ("PURGE", Some(Method::Extension("PURGE".to_string()))),
+        (
+            "VERSION-CONTROL",
+            Some(Method::Extension("VERSION-CONTROL".to_string())),
+        ),
+        // methods are case-sensitive, lowercase get is some other method
+        ("get", Some(Method::Extension("get".to_string()))),
+        ("", None),
+        ("G@T", None),
+        ("GE T", None),
+        ("G\u{e9}T", None),
+    ];
+
+    for (input, expected) in tests {
+        let actual = Method::from_str(input).ok();
+        assert_eq!(actual, expected, "{:?}", input);
+        if let Some(method) = actual {
+            assert_eq!(method.to_string(), input);
         }
     }
 }
+
+#[test]
+fn test_properties() {
+    // (method, safe, idempotent, cacheable)
+    let tests = vec![
+        ("GET", true, true, true),
+        ("HEAD", true, true, true),
+        ("OPTIONS", true, true, false),
+        ("TRACE", true, true, false),
+        ("PUT", false, true, false),
+        ("DELETE", false, true, false),
+        ("POST", false, false, false),
+        ("PATCH", false, false, false),
+        ("CONNECT", false, false, false),
+        ("PROPFIND", true, true, false),
+        ("MKCOL", false, true, false),
+        ("LOCK", false, false, false),
+        ("PURGE", false, false, false),
+    ];
+
+    for (input, safe, idempotent, cacheable) in tests {
+        let method = Method::from_str(input).unwrap();
+        assert_eq!(method.is_safe(), safe, "{}", input);
+        assert_eq!(method.is_idempotent(), idempotent, "{}", input);
+        assert_eq!(method.is_cacheable(), cacheable, "{}", input);
+    }
+}
@@ -1,30 +1,73 @@
-use crate::{http_method::Method, utils};
-use std::{collections::HashMap, io::Read};
-use url::Url;
+use crate::{
+    error::ProxyError, forwarding::strip_hop_by_hop, header_map::HeaderMap, http_method::Method,
+    http_version::Version, utils,
+};
+use std::io::Read;
+use tokio::io::AsyncBufRead;
+use url::{Position, Url};
 // struct to represent HTTP Request
 #[derive(Debug, Clone)]
 pub struct HttpRequest {
     pub method: Method,
     pub url: Url,
-    // headers i a map of string to vec string
-    pub headers: HashMap<String, String>,
-    pub body: String,
+    // origin-form target (path and query) exactly as the client sent it, forwarded
+    // unchanged so upstreams see the original percent-encoding. None derives it from url
+    pub target: Option<String>,
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
 }
 
 impl HttpRequest {
-    pub fn from_stream(stream: &mut dyn Read) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn from_stream(stream: &mut dyn Read) -> Result<Self, ProxyError> {
         utils::read_request(stream)
     }
 
-    pub fn serialize(&self) -> String {
-        let mut request_string = format!("{} {} HTTP/1.1\r\n", self.method, self.url.path());
-        for (key, value) in self.headers.iter() {
+    pub async fn from_stream_async<R: AsyncBufRead + Unpin>(
+        stream: &mut R,
+    ) -> Result<Self, ProxyError> {
+        utils::read_request_async(stream).await
+    }
+
+    // whether the client wants the connection kept open after the response.
+    // HTTP/1.1 defaults to keep-alive, HTTP/1.0 has to ask for it
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !utils::connection_has_token(&self.headers, "close"),
+            Version::Http10 => utils::connection_has_token(&self.headers, "keep-alive"),
+        }
+    }
+
+    // body as text, fails if it is not valid utf-8
+    pub fn body_text(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut request_bytes = self.serialize_head();
+        request_bytes.extend(utils::serialize_body(&self.headers, &self.body));
+        request_bytes
+    }
+
+    // path and query for the request line, fragments are never sent
+    pub fn origin_form(&self) -> &str {
+        match &self.target {
+            Some(target) => target,
+            None => &self.url[Position::BeforePath..Position::AfterQuery],
+        }
+    }
+
+    // request line and headers, for when the body is streamed separately.
+    // hop-by-hop headers were meant for the connection the request came in on and are left out
+    pub fn serialize_head(&self) -> Vec<u8> {
+        let mut headers = self.headers.clone();
+        strip_hop_by_hop(&mut headers);
+        let mut request_string = format!("{} {} HTTP/1.1\r\n", self.method, self.origin_form());
+        for (key, value) in headers.iter() {
             request_string.push_str(format!("{}: {}\r\n", key, value).as_str());
         }
         request_string.push_str("\r\n");
-        request_string.push_str(self.body.as_str());
-        request_string.push_str("\r\n");
-        request_string
+        request_string.into_bytes()
     }
 }
 
@@ -37,7 +80,7 @@ fn test_httprequest_from_string() {
 
     let request = HttpRequest::from_stream(&mut dummy_request).unwrap();
     assert_eq!(request.method, Method::Get);
-    assert_eq!(request.url, Url::parse("localhost:8080/").unwrap());
+    assert_eq!(request.url, Url::parse("http://localhost:8080/").unwrap());
     assert_eq!(request.headers.get("Host").unwrap(), "localhost:8080");
     assert_eq!(request.headers.get("User-Agent").unwrap(), "curl/7.64.1");
     assert_eq!(request.headers.get("Accept").unwrap(), "*/*");
@@ -61,16 +104,18 @@ fn test_from_string() {
             input: "GET / HTTP/1.1\r\nHost: localhost:8080\r\nUser-Agent: curl/7.64.1\r\nAccept: */*\r\n\r\n".to_string(),
             expected: Some(HttpRequest {
                 method: Method::Get,
+                version: Version::Http11,
                 headers: [
+                    ("Host".to_string(), "localhost:8080".to_string()),
                     ("User-Agent".to_string(), "curl/7.64.1".to_string()),
                     ("Accept".to_string(), "*/*".to_string()),
-                    ("Host".to_string(), "localhost:8080".to_string()),
                 ]
                 .iter()
                 .cloned()
                 .collect(),
-                body: "".to_string(),
-                url: url::Url::parse("localhost:8080/").unwrap(),
+                body: Vec::new(),
+                url: url::Url::parse("http://localhost:8080/").unwrap(),
+                target: None,
             }),
             expected_error: false,
         },
@@ -103,3 +148,126 @@ fn test_from_string() {
         assert_eq!(actual.body, test_case.expected.as_ref().unwrap().body);
     }
 }
+
+#[test]
+fn test_keep_alive() {
+    let tests = vec![
+        ("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n", true),
+        (
+            "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            false,
+        ),
+        (
+            "GET / HTTP/1.1\r\nHost: localhost\r\nconnection: Upgrade, Close\r\n\r\n",
+            false,
+        ),
+        ("GET / HTTP/1.0\r\nHost: localhost\r\n\r\n", false),
+        (
+            "GET / HTTP/1.0\r\nHost: localhost\r\nConnection: Keep-Alive\r\n\r\n",
+            true,
+        ),
+    ];
+
+    for (input, expected) in tests {
+        let request = HttpRequest::from_stream(&mut input.as_bytes()).unwrap();
+        assert_eq!(request.keep_alive(), expected, "{}", input);
+    }
+}
+
+#[test]
+fn test_binary_body_round_trip() {
+    // every byte value, including NUL and invalid utf-8 sequences
+    let body: Vec<u8> = (0..=255u8).chain([0xff, 0xfe, 0x00, 0xc3]).collect();
+    let request = HttpRequest {
+        method: Method::Post,
+        url: Url::parse("http://example.com/upload").unwrap(),
+        target: None,
+        version: Version::Http11,
+        headers: HeaderMap::from([
+            ("Host".to_string(), "example.com".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ]),
+        body: body.clone(),
+    };
+
+    let serialized = request.serialize();
+    let parsed = HttpRequest::from_stream(&mut serialized.as_slice()).unwrap();
+    assert_eq!(parsed.body, body);
+    assert!(parsed.body_text().is_err());
+}
+
+#[test]
+fn test_request_target_round_trip() {
+    let tests = vec![
+        ("GET /search?q=rust HTTP/1.1", "/search?q=rust"),
+        (
+            "GET /a%2Fb/%E2%82%AC/%7euser?x=%20y&z=a+b HTTP/1.1",
+            "/a%2Fb/%E2%82%AC/%7euser?x=%20y&z=a+b",
+        ),
+        ("GET /path? HTTP/1.1", "/path?"),
+        ("GET /path?#top HTTP/1.1", "/path?"),
+        ("GET /page#section HTTP/1.1", "/page"),
+        ("GET /a/../b//c HTTP/1.1", "/a/../b//c"),
+        (
+            "GET http://localhost/search?q=a%26b#x HTTP/1.1",
+            "/search?q=a%26b",
+        ),
+        ("GET http://localhost HTTP/1.1", "/"),
+        ("GET http://localhost?q HTTP/1.1", "/?q"),
+        ("GET http://user@localhost:8080/x?y HTTP/1.1", "/x?y"),
+    ];
+
+    for (request_line, expected) in tests {
+        let input = format!("{}\r\nHost: localhost\r\n\r\n", request_line);
+        let request = HttpRequest::from_stream(&mut input.as_bytes()).unwrap();
+        let serialized = String::from_utf8(request.serialize()).unwrap();
+        assert!(
+            serialized.starts_with(&format!("GET {} HTTP/1.1\r\n", expected)),
+            "{}",
+            request_line
+        );
+    }
+}
+
+#[test]
+fn test_origin_form_from_url() {
+    let tests = vec![
+        ("http://example.com", "/"),
+        ("http://example.com/search?q=rust", "/search?q=rust"),
+        ("http://example.com/a b?q=1#fragment", "/a%20b?q=1"),
+        ("http://example.com/path?", "/path?"),
+    ];
+
+    for (url, expected) in tests {
+        let request = HttpRequest {
+            method: Method::Get,
+            url: Url::parse(url).unwrap(),
+            target: None,
+            version: Version::Http11,
+            headers: HeaderMap::new(),
+            body: Vec::new(),
+        };
+        assert_eq!(request.origin_form(), expected, "{}", url);
+    }
+}
+
+#[test]
+fn test_extension_method_round_trip() {
+    let input = "PROPFIND /calendars/ HTTP/1.1\r\nHost: localhost\r\nDepth: 1\r\n\r\n";
+    let request = HttpRequest::from_stream(&mut input.as_bytes()).unwrap();
+    assert_eq!(request.method, Method::Extension("PROPFIND".to_string()));
+    assert!(request.method.is_safe());
+    assert_eq!(request.serialize(), input.as_bytes());
+}
+
+#[test]
+fn test_serialize_strips_hop_by_hop_headers() {
+    let input = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: keep-alive, X-Hop\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nAccept: */*\r\n\r\n";
+
+    let request = HttpRequest::from_stream(&mut input.as_bytes()).unwrap();
+
+    assert_eq!(
+        request.serialize(),
+        b"GET / HTTP/1.1\r\nHost: localhost\r\nAccept: */*\r\n\r\n"
+    );
+}
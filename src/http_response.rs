@@ -1,39 +1,78 @@
-use crate::{status_code::StatusCode, utils};
-use std::{collections::HashMap, io::Read};
+use crate::{
+    error::ProxyError, header_map::HeaderMap, http_method::Method, http_version::Version,
+    status_code::StatusCode, utils,
+};
+use std::io::Read;
+use tokio::io::AsyncBufRead;
 // struct to represent HTTP Response
 #[derive(Debug, Clone)]
 pub struct HttpResponse {
     pub status_code: StatusCode,
-    pub headers: HashMap<String, String>,
-    pub body: String,
+    // reason phrase as received, forwarded verbatim. None uses the registered phrase
+    pub reason: Option<String>,
+    // version the response was received with, serialize always writes HTTP/1.1
+    pub version: Version,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
 }
 
 impl HttpResponse {
-    pub fn from_stream(stream: &mut dyn Read) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn from_stream(stream: &mut dyn Read) -> Result<Self, ProxyError> {
         utils::read_response(stream)
     }
-    pub fn serialize(&self) -> String {
-        let reason_phrase = self.status_code.to_reason_phrase();
-        let mut headers_vec: Vec<String> = Vec::new();
-
-        for header in self.headers.iter() {
-            let key = header.0;
-            let value = header.1;
-            headers_vec.insert(0, format!("{}: {}\r\n", key, value));
+
+    pub async fn from_stream_async<R: AsyncBufRead + Unpin>(
+        stream: &mut R,
+    ) -> Result<Self, ProxyError> {
+        utils::read_response_async(stream).await
+    }
+
+    // read the response to a request sent with request_method, responses to HEAD carry no body
+    pub async fn from_stream_for_async<R: AsyncBufRead + Unpin>(
+        stream: &mut R,
+        request_method: &Method,
+    ) -> Result<Self, ProxyError> {
+        utils::read_response_for_async(stream, request_method).await
+    }
+
+    // whether the sender keeps the connection open after this response.
+    // HTTP/1.1 defaults to keep-alive, HTTP/1.0 has to ask for it
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !utils::connection_has_token(&self.headers, "close"),
+            Version::Http10 => utils::connection_has_token(&self.headers, "keep-alive"),
         }
+    }
+
+    // body as text, fails if it is not valid utf-8
+    pub fn body_text(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.body)
+    }
 
-        // sort lexicographically
-        headers_vec.sort();
-        let headers = headers_vec.join("");
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut response_bytes = self.serialize_head();
+        response_bytes.extend(utils::serialize_body(&self.headers, &self.body));
+        response_bytes
+    }
+
+    // status line and headers, for when the body is streamed separately
+    pub fn serialize_head(&self) -> Vec<u8> {
+        let reason_phrase = match &self.reason {
+            Some(reason) => reason,
+            None => self.status_code.to_reason_phrase(),
+        };
+        let mut headers = String::new();
+        for (key, value) in self.headers.iter() {
+            headers.push_str(format!("{}: {}\r\n", key, value).as_str());
+        }
 
         format!(
-            "HTTP/1.1 {} {}\r\n{}\r\n{}",
+            "HTTP/1.1 {} {}\r\n{}\r\n",
             self.status_code.to_u32(),
             reason_phrase,
             headers,
-            self.body
         )
-        .replace('\0', "")
+        .into_bytes()
     }
 }
 
@@ -55,6 +94,8 @@ fn test_serialize() {
             input: "HTTP/1.1 200 OK\r\nDate: Mon, 23 May 2023 22:38:34 GMT\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 132\r\n\r\n<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
             expected: Some(HttpResponse {
                 status_code: StatusCode::OK,
+                reason: None,
+                version: Version::Http11,
                 headers: [
                     ("Date".to_string(), "Mon, 23 May 2023 22:38:34 GMT".to_string()),
                     ("Content-Type".to_string(), "text/html; charset=UTF-8".to_string()),
@@ -63,7 +104,7 @@ fn test_serialize() {
                 .iter()
                 .cloned()
                 .collect(),
-                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
+                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".into(),
             }),
             expected_error: false,
         },
@@ -72,6 +113,8 @@ fn test_serialize() {
             input: "HTTP/1.1 404 Not Found\r\nDate: Mon, 23 May 2023 22:38:34 GMT\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 132\r\n\r\n<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
             expected: Some(HttpResponse {
                 status_code: StatusCode::NotFound,
+                reason: None,
+                version: Version::Http11,
                 headers: [
                     ("Date".to_string(), "Mon, 23 May 2023 22:38:34 GMT".to_string()),
                     ("Content-Type".to_string(), "text/html; charset=UTF-8".to_string()),
@@ -80,7 +123,7 @@ fn test_serialize() {
                 .iter()
                 .cloned()
                 .collect(),
-                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
+                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".into(),
             }),
             expected_error: false,
         },
@@ -89,6 +132,8 @@ fn test_serialize() {
             input: "HTTP/1.1 301 Moved Permanently\r\nLocation: https://www.example.com/\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 159\r\n\r\n<html>\r\n<head><title>301 Moved Permanently</title></head>\r\n<body>\r\n<p>The document has moved <a href=\"https://www.example.com/\">here</a>.</p>\r\n</body>\r\n</html>".to_string(),
             expected: Some(HttpResponse {
                 status_code: StatusCode::MovedPermanently,
+                reason: None,
+                version: Version::Http11,
                 headers: [
                     ("Location".to_string(), "https://www.example.com/".to_string()),
                     ("Content-Type".to_string(), "text/html; charset=UTF-8".to_string()),
@@ -97,7 +142,7 @@ fn test_serialize() {
                 .iter()
                 .cloned()
                 .collect(),
-                body: "<html>\r\n<head><title>301 Moved Permanently</title></head>\r\n<body>\r\n<p>The document has moved <a href=\"https://www.example.com/\">here</a>.</p>\r\n</body>\r\n</html>".to_string(),
+                body: "<html>\r\n<head><title>301 Moved Permanently</title></head>\r\n<body>\r\n<p>The document has moved <a href=\"https://www.example.com/\">here</a>.</p>\r\n</body>\r\n</html>".into(),
             }),
             expected_error: false,
         },
@@ -106,15 +151,17 @@ fn test_serialize() {
             input: "HTTP/1.1 405 Method Not Allowed\r\nDate: Mon, 23 May 2023 22:38:34 GMT\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 132\r\n\r\n<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
             expected: Some(HttpResponse {
                 status_code: StatusCode::MethodNotAllowed,
+                reason: None,
+                version: Version::Http11,
                 headers: [
+                    ("Date".to_string(), "Mon, 23 May 2023 22:38:34 GMT".to_string()),
                     ("Content-Type".to_string(), "text/html; charset=UTF-8".to_string()),
                     ("Content-Length".to_string(), "132".to_string()),
-                    ("Date".to_string(), "Mon, 23 May 2023 22:38:34 GMT".to_string()),
                 ]
                 .iter()
                 .cloned()
                 .collect(),
-                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
+                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".into(),
             }),
             expected_error: false,
         },
@@ -129,15 +176,17 @@ fn test_serialize() {
             input: "HTTP/1.1 200 OK\r\nDate: Mon, 23 May 2023 22:38:34 GMT\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 11\r\n\r\nhello world".to_string(),
             expected: Some(HttpResponse {
                 status_code: StatusCode::OK,
+                reason: None,
+                version: Version::Http11,
                 headers: [
+                    ("Date".to_string(), "Mon, 23 May 2023 22:38:34 GMT".to_string()),
                     ("Content-Type".to_string(), "text/html; charset=UTF-8".to_string()),
                     ("Content-Length".to_string(), "11".to_string()),
-                    ("Date".to_string(), "Mon, 23 May 2023 22:38:34 GMT".to_string()),
                 ]
                 .iter()
                 .cloned()
                 .collect(),
-                body: "hello world".to_string(),
+                body: "hello world".into(),
             }),
             expected_error: false,
         },
@@ -193,6 +242,8 @@ fn test_to_string() {
             _name: "simple 200 OK".to_string(),
             input: HttpResponse {
                 status_code: StatusCode::OK,
+                reason: None,
+                version: Version::Http11,
                 headers: [
                     ("Content-Length".to_string(), "138".to_string()),
                     ("Content-Type".to_string(), "text/html; charset=UTF-8".to_string()),
@@ -201,7 +252,7 @@ fn test_to_string() {
                 .iter()
                 .cloned()
                 .collect(),
-                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
+                body: "<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".into(),
             },
             expected: "HTTP/1.1 200 OK\r\nContent-Length: 138\r\nContent-Type: text/html; charset=UTF-8\r\nDate: Mon, 23 May 2023 22:38:34 GMT\r\n\r\n<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
         },
@@ -213,7 +264,7 @@ fn test_to_string() {
         let actual = test_case.input.serialize();
 
         // assert that actual is equal to expected
-        assert_eq!(actual, test_case.expected, "{}", test_case._name);
+        assert_eq!(actual, test_case.expected.as_bytes(), "{}", test_case._name);
     }
 }
 
@@ -221,3 +272,65 @@ fn test_to_string() {
 // fn test_from_socket() {
 //     let socket = TcpStream::
 // }
+
+#[test]
+fn test_binary_body_round_trip() {
+    // a gzip header followed by NUL bytes and invalid utf-8
+    let body: Vec<u8> = vec![0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0xff, 0xfe, 0x80];
+    let response = HttpResponse {
+        status_code: StatusCode::OK,
+        reason: None,
+        version: Version::Http11,
+        headers: HeaderMap::from([
+            ("Content-Encoding".to_string(), "gzip".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ]),
+        body: body.clone(),
+    };
+
+    let serialized = response.serialize();
+    let parsed = HttpResponse::from_stream(&mut serialized.as_slice()).unwrap();
+    assert_eq!(parsed.body, body);
+    assert!(parsed.body_text().is_err());
+
+    let chunked = HttpResponse {
+        headers: HeaderMap::from([("Transfer-Encoding".to_string(), "chunked".to_string())]),
+        ..response
+    };
+    let serialized = chunked.serialize();
+    let parsed = HttpResponse::from_stream(&mut serialized.as_slice()).unwrap();
+    assert_eq!(parsed.body, body);
+}
+
+#[test]
+fn test_repeated_headers_round_trip() {
+    let input = "HTTP/1.1 200 OK\r\nset-cookie: a=1\r\nContent-Length: 0\r\nSet-Cookie: b=2\r\nX-Request-ID: 7\r\n\r\n";
+    let response = HttpResponse::from_stream(&mut input.as_bytes()).unwrap();
+
+    assert_eq!(response.headers.get("content-length"), Some("0"));
+    assert_eq!(
+        response.headers.get_all("Set-Cookie").collect::<Vec<_>>(),
+        vec!["a=1", "b=2"]
+    );
+    // order and casing survive serialization
+    assert_eq!(response.serialize(), input.as_bytes());
+}
+
+#[test]
+fn test_unknown_status_round_trip() {
+    let tests = vec![
+        "HTTP/1.1 299 Custom Success\r\nContent-Length: 0\r\n\r\n",
+        "HTTP/1.1 429 Slow  Down\r\nContent-Length: 0\r\n\r\n",
+        "HTTP/1.1 204 \r\n\r\n",
+    ];
+
+    for input in tests {
+        let response = HttpResponse::from_stream(&mut input.as_bytes()).unwrap();
+        assert_eq!(response.serialize(), input.as_bytes(), "{}", input);
+    }
+
+    let response =
+        HttpResponse::from_stream(&mut "HTTP/1.1 599 Whatever\r\n\r\n".as_bytes()).unwrap();
+    assert_eq!(response.status_code, StatusCode::Other(599));
+    assert!(response.status_code.is_server_error());
+}
@@ -0,0 +1,38 @@
+use std::{fmt, str::FromStr};
+
+use crate::error::ProxyError;
+
+// enum for protocol versions implements Display trait
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Version::Http10 => "HTTP/1.0",
+                Version::Http11 => "HTTP/1.1",
+            }
+        )
+    }
+}
+
+impl FromStr for Version {
+    type Err = ProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HTTP/1.0" => Ok(Version::Http10),
+            "HTTP/1.1" => Ok(Version::Http11),
+            _ => Err(ProxyError::Parse(format!(
+                "unsupported http version {:?}",
+                s
+            ))),
+        }
+    }
+}
@@ -0,0 +1,27 @@
+pub mod acl;
+pub mod admin;
+pub mod balancer;
+pub mod body;
+pub mod chunked;
+pub mod config;
+pub mod config_file;
+pub mod connection_pool;
+pub mod default_headers;
+pub mod error;
+pub mod forwarding;
+pub mod header_map;
+pub mod health;
+pub mod http_client;
+pub mod http_method;
+pub mod http_request;
+pub mod http_response;
+pub mod http_version;
+pub mod reload;
+pub mod retry;
+pub mod routing;
+pub mod server;
+pub mod shutdown;
+pub mod status_code;
+pub mod timeout;
+pub mod tunnel;
+pub mod utils;
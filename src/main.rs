@@ -1,143 +1,76 @@
-mod http_client;
-mod http_method;
-mod http_request;
-mod http_response;
-mod status_code;
-mod utils;
 extern crate dotenv;
+use std::path::Path;
+
 use dotenv::dotenv;
-use http_response::HttpResponse;
-use status_code::StatusCode;
-use std::collections::HashMap;
-use std::net::TcpListener;
-use std::net::TcpStream;
-use utils::write_to_stream;
+use proxyrs::{config::Config, config_file, server};
 
-use crate::http_client::HTTPClient;
-use crate::http_request::HttpRequest;
+const USAGE: &str = "usage: proxyrs [--config <file>]\n       proxyrs check-config <file>";
 
 fn main() {
-    match dotenv().ok() {
-        Some(_) => println!("dotenv loaded"),
-        None => println!("dotenv not loaded"),
-    }
-    env_logger::init();
-    println!("Starting rust server");
-
-    let address = std::env::var("ADDRESS").unwrap_or_else(|_| "0.0.0.0".to_string());
-    let port = std::env::var("PORT").unwrap_or_else(|_| "9095".to_string());
-    listen(&address, &port);
-}
-
-fn health_handler(socket: &mut TcpStream) {
-    let response = http_response::HttpResponse {
-        status_code: StatusCode::OK,
-        headers: HashMap::from([("Content-Length".to_string(), "2".to_string())]),
-        body: "OK".to_string(),
-    };
-
-    write_to_stream(socket, &response.serialize()).expect("failed to write to socket");
-}
-
-// function to listen incoming tcp connections on port
-fn listen(address: &str, port: &str) {
-    let listener =
-        TcpListener::bind(format!("{}:{}", address, port)).expect("Failed to bind to port");
-    log::info!("Listening on port {}", port);
-    let client = HTTPClient::new(HashMap::new());
-
-    loop {
-        match listener.accept() {
-            Ok((mut socket, addr)) => {
-                log::info!("incoming request from: {:?}", addr);
-                let request = match HttpRequest::from_stream(&mut socket) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        log::error!("failed to read from stream: {:?}", e);
-                        let response = HttpResponse {
-                            status_code: StatusCode::InvalidRequest,
-                            headers: HashMap::new(),
-                            body: "Bad Request".to_string(),
-                        };
-                        write_to_stream(&mut socket, &response.serialize())
-                            .expect("failed to write to socket");
-                        close_socket(socket);
-                        continue;
-                    }
-                };
-
-                if request.url.as_str().contains("/health")
-                    || request.url.as_str().contains("/favicon.ico")
-                {
-                    log::debug!("ignore request: {:?}", request.clone());
-                    health_handler(&mut socket);
-                    close_socket(socket);
-                    continue;
-                }
-
-                let actual_response = match client.execute(request) {
-                    Ok(response) => response,
-                    Err(e) => {
-                        log::error!("failed to execute request: {:?}", e);
-                        let response = HttpResponse {
-                            status_code: StatusCode::InternalServerError,
-                            headers: HashMap::new(),
-                            body: "Bad Request".to_string(),
-                        };
-                        write_to_stream(&mut socket, &response.serialize())
-                            .expect("failed to write to socket");
-                        close_socket(socket);
-                        continue;
-                    }
-                };
-
-                write_to_stream(&mut socket, &actual_response.serialize())
-                    .expect("failed to write to socket");
-                close_socket(socket)
-            }
-            Err(e) => {
-                log::error!("failed to accept connection: {:?}", e);
-            }
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        [] => run(None),
+        ["--config" | "-c", file] => run(Some(file.to_string())),
+        ["check-config", file] => check_config(file),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
         }
     }
 }
 
-fn close_socket(socket: TcpStream) {
-    let res = socket.shutdown(std::net::Shutdown::Both);
-    match res {
-        Ok(_num_bytes) => {
-            log::debug!("successfully closed socket");
-        }
-        Err(_e) => {
-            log::error!("failed to close socket {:?}", _e);
+// validates the file without starting anything
+fn check_config(file: &str) {
+    match config_file::load(Path::new(file)) {
+        Ok(_) => println!("{}: ok", file),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     }
 }
 
-// tests
-#[test]
-fn test_listen() {
-    // skip
-    return;
-    use std::thread;
-
-    let _handle = thread::spawn(|| listen("localhost", "5656"));
+fn run(config_path: Option<String>) {
+    match dotenv().ok() {
+        Some(_) => println!("dotenv loaded"),
+        None => println!("dotenv not loaded"),
+    }
 
-    let client = HTTPClient::new(HashMap::new());
+    // a config file replaces the environment variables, only RUST_LOG still applies.
+    // it is reloaded when it changes or on SIGHUP
+    let config_path = config_path.or_else(|| std::env::var("CONFIG_FILE").ok());
+    let config = match &config_path {
+        Some(path) => match config_file::load(Path::new(path)) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("invalid config: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Config::from_env(),
+    };
 
-    let request = HttpRequest {
-        method: crate::http_method::Method::Get,
-        body: "".to_string(),
-        url: url::Url::parse("http://localhost:5656/health").unwrap(),
-        headers: HashMap::from([("Host".to_string(), "http://google.com".to_string())]),
+    let env = match &config.log_level {
+        Some(level) => env_logger::Env::default().default_filter_or(level.as_str()),
+        None => env_logger::Env::default(),
     };
+    env_logger::Builder::from_env(env).init();
+    println!("Starting rust server");
 
-    let response = client.execute(request);
-    assert!(response.is_ok());
-    match response {
-        Ok(r) => {
-            assert_eq!("OK", r.body.trim())
-        }
-        Err(_err) => {}
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .enable_all()
+        .build()
+        .expect("failed to build tokio runtime");
+    let served = match config_path {
+        Some(path) => runtime.block_on(server::listen_with_reload(&config, path.into())),
+        None => runtime.block_on(server::listen(&config)),
+    };
+    if let Err(e) = served {
+        log::error!("server failed: {}", e);
+        eprintln!("server failed: {}", e);
+        std::process::exit(1);
     }
+    println!("Stopped rust server");
 }
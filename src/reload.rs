@@ -0,0 +1,204 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+
+use crate::{config::Config, config_file};
+
+// how often the config file is checked for changes
+pub const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// sends the config file again every time its contents change or the process gets
+// SIGHUP. a file that fails to load is logged and skipped, whoever receives keeps
+// the config they have. stops once the receiver is dropped
+pub fn watch_config(path: PathBuf, poll_interval: Duration) -> mpsc::Receiver<Config> {
+    let (sender, receiver) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut hangup = Hangup::new();
+        let mut last_contents = read_file(&path).await;
+        let mut poll = tokio::time::interval(poll_interval);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {
+                    // comparing contents catches edits within the same mtime tick
+                    // and files replaced by renaming another one over them
+                    let contents = read_file(&path).await;
+                    if contents == last_contents {
+                        continue;
+                    }
+                    last_contents = contents;
+                    log::info!("config file {} changed, reloading", path.display());
+                }
+                _ = hangup.recv() => {
+                    log::info!("received SIGHUP, reloading {}", path.display());
+                }
+                _ = sender.closed() => break,
+            }
+            match load(&path).await {
+                Ok(config) => {
+                    if sender.send(config).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => log::error!("invalid config, keeping the current one: {}", e),
+            }
+        }
+    });
+    receiver
+}
+
+// file reads block, they run on tokio's blocking pool like the parsing in load
+async fn read_file(path: &Path) -> Option<Vec<u8>> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || std::fs::read(path).ok())
+        .await
+        .ok()
+        .flatten()
+}
+
+async fn load(path: &Path) -> Result<Config, String> {
+    let path = path.to_path_buf();
+    match tokio::task::spawn_blocking(move || config_file::load(&path)).await {
+        Ok(loaded) => loaded.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// the listeners, the runtime and the logger are set up once at startup, changes to
+// their settings are reported and ignored until the next restart
+pub fn merge_reloaded(current: &Config, new: Config) -> Config {
+    let restart_only = [
+        ("listener.address", current.address != new.address),
+        ("listener.port", current.port != new.port),
+        ("listener.workers", current.workers != new.workers),
+        (
+            "listener.max_connections",
+            current.max_connections != new.max_connections,
+        ),
+        (
+            "listener.accept_backlog",
+            current.accept_backlog != new.accept_backlog,
+        ),
+        ("admin.address", current.admin_address != new.admin_address),
+        ("admin.port", current.admin_port != new.admin_port),
+        ("logging.level", current.log_level != new.log_level),
+    ];
+    for (key, changed) in restart_only {
+        if changed {
+            log::warn!("{} changed, it takes effect after a restart", key);
+        }
+    }
+
+    Config {
+        address: current.address.clone(),
+        port: current.port.clone(),
+        workers: current.workers,
+        max_connections: current.max_connections,
+        accept_backlog: current.accept_backlog,
+        admin_address: current.admin_address.clone(),
+        admin_port: current.admin_port.clone(),
+        log_level: current.log_level.clone(),
+        ..new
+    }
+}
+
+#[cfg(unix)]
+struct Hangup(Option<tokio::signal::unix::Signal>);
+
+#[cfg(unix)]
+impl Hangup {
+    fn new() -> Self {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::hangup()) {
+            Ok(hangup) => Hangup(Some(hangup)),
+            Err(e) => {
+                log::error!("failed to listen for SIGHUP: {:?}", e);
+                Hangup(None)
+            }
+        }
+    }
+
+    // resolves on every SIGHUP
+    async fn recv(&mut self) {
+        if let Some(hangup) = &mut self.0 {
+            if hangup.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+#[cfg(not(unix))]
+struct Hangup;
+
+#[cfg(not(unix))]
+impl Hangup {
+    fn new() -> Self {
+        Hangup
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+#[test]
+fn test_merge_reloaded() {
+    let current = Config::default();
+    let new = Config {
+        port: "8080".to_string(),
+        workers: 1,
+        accept_backlog: 16,
+        admin_port: None,
+        keep_alive_timeout: Duration::from_secs(5),
+        shutdown_timeout: Duration::from_secs(5),
+        connect_ports: vec![443, 8443],
+        ..Config::default()
+    };
+
+    let merged = merge_reloaded(&current, new);
+    assert_eq!(merged.port, current.port);
+    assert_eq!(merged.workers, current.workers);
+    assert_eq!(merged.accept_backlog, current.accept_backlog);
+    assert_eq!(merged.admin_port, current.admin_port);
+    assert_eq!(merged.keep_alive_timeout, Duration::from_secs(5));
+    assert_eq!(merged.shutdown_timeout, Duration::from_secs(5));
+    assert_eq!(merged.connect_ports, vec![443, 8443]);
+}
+
+#[cfg(test)]
+async fn next(reloads: &mut mpsc::Receiver<Config>) -> Option<Config> {
+    tokio::time::timeout(Duration::from_millis(500), reloads.recv())
+        .await
+        .ok()
+        .flatten()
+}
+
+#[tokio::test]
+async fn test_watch_config() {
+    let path = std::env::temp_dir().join(format!("proxyrs-reload-{}.toml", std::process::id()));
+    std::fs::write(&path, "[listener]\nkeep_alive_timeout = 1\n").unwrap();
+    let mut reloads = watch_config(path.clone(), Duration::from_millis(10));
+    // nothing is sent until the file changes
+    assert!(next(&mut reloads).await.is_none());
+
+    std::fs::write(&path, "[listener]\nkeep_alive_timeout = 2\n").unwrap();
+    let config = next(&mut reloads).await.unwrap();
+    assert_eq!(config.keep_alive_timeout, Duration::from_secs(2));
+
+    // an invalid file is skipped
+    std::fs::write(&path, "[listener]\nkeep_alive_timeout = \"soon\"\n").unwrap();
+    assert!(next(&mut reloads).await.is_none());
+
+    std::fs::write(&path, "[listener]\nkeep_alive_timeout = 3\n").unwrap();
+    let config = next(&mut reloads).await.unwrap();
+    assert_eq!(config.keep_alive_timeout, Duration::from_secs(3));
+
+    std::fs::remove_file(&path).unwrap();
+}
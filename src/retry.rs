@@ -0,0 +1,184 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{error::ProxyError, status_code::StatusCode, utils::random_u64};
+
+// when and how often a failed upstream request is sent again
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    // attempts per request including the first one, 1 disables retries
+    pub max_attempts: usize,
+    // wait before the first retry, doubled for every further one
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    // upstream answers that are worth trying again
+    pub retry_statuses: Vec<StatusCode>,
+    // retries per route allowed on top of the requests, in percent of the
+    // requests sent to the route during the last budget_window
+    pub budget_percent: usize,
+    // retries per route and budget_window that are always allowed, so routes
+    // with little traffic can retry too
+    pub budget_min_retries: usize,
+    pub budget_window: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+            retry_statuses: vec![
+                StatusCode::BadGateway,
+                StatusCode::ServiceUnavailable,
+                StatusCode::GatewayTimeout,
+            ],
+            budget_percent: 20,
+            budget_min_retries: 10,
+            budget_window: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // errors a request can fail with that are worth trying again.
+    // a connect error, a connect timeout included, means the request never reached the upstream
+    pub fn is_retryable_error(&self, error: &ProxyError) -> bool {
+        matches!(error, ProxyError::Connect(_))
+    }
+
+    pub fn is_retryable_status(&self, status_code: StatusCode) -> bool {
+        self.retry_statuses.contains(&status_code)
+    }
+
+    // wait before retry number retry (starting at 1). exponential backoff with
+    // full jitter, so clients that failed together do not retry together
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let exponent = retry.saturating_sub(1).min(31) as u32;
+        let ceiling = self
+            .base_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        ceiling.mul_f64(random_fraction())
+    }
+}
+
+// uniformly distributed in [0, 1)
+fn random_fraction() -> f64 {
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+struct BudgetWindow {
+    started: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+// keeps retries to a share of the requests of every route, so retrying cannot
+// multiply the load on an upstream that is already failing
+pub struct RetryBudget {
+    percent: usize,
+    min_retries: usize,
+    window: Duration,
+    routes: Mutex<HashMap<String, BudgetWindow>>,
+}
+
+impl RetryBudget {
+    pub fn new(policy: &RetryPolicy) -> Self {
+        Self {
+            percent: policy.budget_percent,
+            min_retries: policy.budget_min_retries,
+            window: policy.budget_window,
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // count a request sent to route for the first time
+    pub fn record_request(&self, route: &str) {
+        let mut routes = self.routes.lock().unwrap();
+        self.current_window(&mut routes, route).requests += 1;
+    }
+
+    // takes a retry from the budget of route, false if it is used up
+    pub fn try_retry(&self, route: &str) -> bool {
+        let mut routes = self.routes.lock().unwrap();
+        let (percent, min_retries) = (self.percent, self.min_retries);
+        let window = self.current_window(&mut routes, route);
+        let allowed = min_retries.max(window.requests * percent / 100);
+        if window.retries < allowed {
+            window.retries += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn current_window<'a>(
+        &self,
+        routes: &'a mut HashMap<String, BudgetWindow>,
+        route: &str,
+    ) -> &'a mut BudgetWindow {
+        let window = routes
+            .entry(route.to_string())
+            .or_insert_with(|| BudgetWindow {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            });
+        if window.started.elapsed() >= self.window {
+            *window = BudgetWindow {
+                started: Instant::now(),
+                requests: 0,
+                retries: 0,
+            };
+        }
+        window
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let policy = RetryPolicy {
+        base_backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(300),
+        ..RetryPolicy::default()
+    };
+    let tests = vec![
+        (1, Duration::from_millis(100)),
+        (2, Duration::from_millis(200)),
+        (3, Duration::from_millis(300)),
+        (40, Duration::from_millis(300)),
+    ];
+
+    for (retry, ceiling) in tests {
+        for _ in 0..20 {
+            assert!(policy.backoff(retry) < ceiling, "{}", retry);
+        }
+    }
+}
+
+#[test]
+fn test_retry_budget() {
+    let budget = RetryBudget::new(&RetryPolicy {
+        budget_percent: 20,
+        budget_min_retries: 1,
+        ..RetryPolicy::default()
+    });
+
+    // the minimum applies even without traffic
+    assert!(budget.try_retry("a:80"));
+    assert!(!budget.try_retry("a:80"));
+
+    // 20 requests allow 4 retries in total, one of which was taken above
+    for _ in 0..20 {
+        budget.record_request("a:80");
+    }
+    let retries = (0..10).filter(|_| budget.try_retry("a:80")).count();
+    assert_eq!(retries, 3);
+
+    // every route has a budget of its own
+    assert!(budget.try_retry("b:80"));
+}
@@ -0,0 +1,456 @@
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::{
+    acl::host_matches,
+    balancer::{Balancer, Endpoint, Policy},
+    health::HealthCheck,
+    http_method::Method,
+    http_request::HttpRequest,
+};
+
+// a named group of upstream servers that serve the same thing
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub name: String,
+    pub balancer: Arc<Balancer>,
+    // endpoints are only probed if set
+    pub health_check: Option<HealthCheck>,
+}
+
+impl Cluster {
+    pub fn new(
+        name: impl Into<String>,
+        policy: Policy,
+        endpoints: Vec<Endpoint>,
+    ) -> Result<Self, String> {
+        let name = name.into();
+        let balancer =
+            Balancer::new(policy, endpoints).map_err(|e| format!("cluster {:?}: {}", name, e))?;
+        Ok(Self {
+            name,
+            balancer: Arc::new(balancer),
+            health_check: None,
+        })
+    }
+
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+}
+
+// which request paths a route takes. a match always starts at the beginning of the path
+#[derive(Debug, Clone)]
+pub enum PathMatch {
+    Any,
+    // whole segments only, "/api" takes "/api" and "/api/users" but not "/apis"
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathMatch {
+    pub fn regex(pattern: &str) -> Result<Self, String> {
+        Regex::new(&format!("^(?:{})", pattern))
+            .map(PathMatch::Regex)
+            .map_err(|e| e.to_string())
+    }
+
+    // length of the part of path that matched
+    fn matched_len(&self, path: &str) -> Option<usize> {
+        match self {
+            PathMatch::Any => Some(0),
+            // a trailing slash stays with the rest of the path, so rewrites keep it
+            PathMatch::Prefix(prefix) => match prefix.strip_suffix('/') {
+                Some(segments) => path.starts_with(prefix.as_str()).then_some(segments.len()),
+                None => {
+                    let rest = path.strip_prefix(prefix.as_str())?;
+                    (rest.is_empty() || rest.starts_with('/')).then_some(prefix.len())
+                }
+            },
+            PathMatch::Regex(regex) => regex.find(path).map(|found| found.end()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    // host the request was sent to, see host_matches. any host if None
+    pub host: Option<String>,
+    pub path: PathMatch,
+    // any method if empty
+    pub methods: Vec<Method>,
+    pub cluster: String,
+    // replaces the part of the path that matched, "" strips a prefix.
+    // regex rewrites can refer to groups as ${1} or ${name}
+    pub rewrite: Option<String>,
+}
+
+impl Route {
+    // the origin-form target to send upstream, None if the route doesn't take the request.
+    // target is the request's origin-form target with dot-segments removed
+    fn target(&self, request: &HttpRequest, target: &str) -> Option<String> {
+        if let Some(host) = &self.host {
+            if !host_matches(host, request.url.host_str().unwrap_or_default()) {
+                return None;
+            }
+        }
+        if !self.methods.is_empty() && !self.methods.contains(&request.method) {
+            return None;
+        }
+
+        let (path, query) = target.split_at(target.find('?').unwrap_or(target.len()));
+        let matched_len = self.path.matched_len(path)?;
+        let rewrite = match &self.rewrite {
+            Some(rewrite) => rewrite,
+            None => return Some(target.to_string()),
+        };
+        let path = match &self.path {
+            PathMatch::Regex(regex) => regex.replace(path, rewrite.as_str()).into_owned(),
+            _ => format!("{}{}", rewrite, &path[matched_len..]),
+        };
+        if path.starts_with('/') {
+            Some(format!("{}{}", path, query))
+        } else {
+            Some(format!("/{}{}", path, query))
+        }
+    }
+}
+
+// maps requests to upstream clusters in reverse proxy mode. routes are tried in
+// order and the first one that takes the request wins
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    // every route with the index of its cluster
+    routes: Vec<(Route, usize)>,
+    clusters: Vec<Cluster>,
+}
+
+impl RoutingTable {
+    pub fn new(routes: Vec<Route>, clusters: Vec<Cluster>) -> Result<Self, String> {
+        for (i, cluster) in clusters.iter().enumerate() {
+            if clusters[..i].iter().any(|other| other.name == cluster.name) {
+                return Err(format!("cluster {:?} is defined twice", cluster.name));
+            }
+        }
+        let routes = routes
+            .into_iter()
+            .map(|route| {
+                match clusters
+                    .iter()
+                    .position(|cluster| cluster.name == route.cluster)
+                {
+                    Some(cluster) => Ok((route, cluster)),
+                    None => Err(format!("route to unknown cluster {:?}", route.cluster)),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { routes, clusters })
+    }
+
+    pub fn clusters(&self) -> &[Cluster] {
+        &self.clusters
+    }
+
+    // endpoints that are in previous as well keep the health they had there, so a
+    // reload doesn't send requests to endpoints known to be down. only clusters that
+    // are still probed take it over, nothing would bring the others back
+    pub fn inherit_health(&self, previous: &RoutingTable) {
+        for cluster in self.clusters.iter().filter(|c| c.health_check.is_some()) {
+            let previous = match previous.clusters.iter().find(|c| c.name == cluster.name) {
+                Some(previous) => previous,
+                None => continue,
+            };
+            for endpoint in cluster.balancer.endpoints() {
+                let known = previous
+                    .balancer
+                    .endpoints()
+                    .iter()
+                    .find(|known| known.address == endpoint.address);
+                if let Some(known) = known {
+                    endpoint.inherit_health(known);
+                }
+            }
+        }
+    }
+
+    // the cluster to send the request to and the target to send it with.
+    // None if no route takes it, CONNECT never is routed. "/api/../internal" is
+    // routed and sent as "/internal", the upstream would resolve it the same way
+    pub fn route(&self, request: &HttpRequest) -> Option<(&Cluster, String)> {
+        if request.method == Method::Connect {
+            return None;
+        }
+        let target = request.origin_form();
+        let (path, query) = target.split_at(target.find('?').unwrap_or(target.len()));
+        let target = format!("{}{}", remove_dot_segments(path), query);
+        self.routes.iter().find_map(|(route, cluster)| {
+            let target = route.target(request, &target)?;
+            Some((&self.clusters[*cluster], target))
+        })
+    }
+}
+
+// path with its "." and ".." segments resolved (RFC 3986 section 5.2.4).
+// percent-encoded dots count as dots, servers decode them before resolving
+fn remove_dot_segments(path: &str) -> String {
+    if !path.starts_with('/') {
+        return path.to_string();
+    }
+    let segments: Vec<&str> = path[1..].split('/').collect();
+    let mut output: Vec<&str> = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        let last = i == segments.len() - 1;
+        match segment.to_ascii_lowercase().replace("%2e", ".").as_str() {
+            "." => {}
+            ".." => {
+                output.pop();
+            }
+            _ => {
+                output.push(segment);
+                continue;
+            }
+        }
+        // "/a/b/.." is the directory "/a/", the trailing slash stays
+        if last {
+            output.push("");
+        }
+    }
+    format!("/{}", output.join("/"))
+}
+
+#[cfg(test)]
+fn cluster(name: &str, address: &str) -> Cluster {
+    Cluster::new(name, Policy::RoundRobin, vec![Endpoint::new(address, 1)]).unwrap()
+}
+
+#[cfg(test)]
+fn route(host: Option<&str>, path: PathMatch, methods: &[Method], cluster: &str) -> Route {
+    Route {
+        host: host.map(str::to_string),
+        path,
+        methods: methods.to_vec(),
+        cluster: cluster.to_string(),
+        rewrite: None,
+    }
+}
+
+#[test]
+fn test_route() {
+    let table = RoutingTable::new(
+        vec![
+            route(
+                Some("admin.example.com"),
+                PathMatch::Any,
+                &[Method::Get],
+                "admin",
+            ),
+            Route {
+                rewrite: Some(String::new()),
+                ..route(None, PathMatch::Prefix("/users".to_string()), &[], "users")
+            },
+            Route {
+                rewrite: Some("/v2/orders/${id}".to_string()),
+                ..route(
+                    None,
+                    PathMatch::regex(r"/orders/(?P<id>[0-9]+)").unwrap(),
+                    &[],
+                    "orders",
+                )
+            },
+            Route {
+                rewrite: Some("/static".to_string()),
+                ..route(
+                    Some("*.cdn.example.com"),
+                    PathMatch::Prefix("/assets/".to_string()),
+                    &[],
+                    "static",
+                )
+            },
+            route(None, PathMatch::Prefix("/".to_string()), &[], "default"),
+        ],
+        ["admin", "users", "orders", "static", "default"]
+            .iter()
+            .map(|name| cluster(name, &format!("{}:8080", name)))
+            .collect(),
+    )
+    .unwrap();
+
+    let tests = vec![
+        (
+            "GET",
+            "admin.example.com",
+            "/anything",
+            "admin",
+            "/anything",
+        ),
+        (
+            "POST",
+            "admin.example.com",
+            "/anything",
+            "default",
+            "/anything",
+        ),
+        ("GET", "example.com", "/users", "users", "/"),
+        (
+            "GET",
+            "example.com",
+            "/users/1?full=1",
+            "users",
+            "/1?full=1",
+        ),
+        (
+            "GET",
+            "example.com",
+            "/usersettings",
+            "default",
+            "/usersettings",
+        ),
+        (
+            "GET",
+            "example.com",
+            "/orders/42/items",
+            "orders",
+            "/v2/orders/42/items",
+        ),
+        (
+            "GET",
+            "example.com",
+            "/orders/new",
+            "default",
+            "/orders/new",
+        ),
+        (
+            "GET",
+            "eu.cdn.example.com",
+            "/assets/a%20b.css",
+            "static",
+            "/static/a%20b.css",
+        ),
+        (
+            "GET",
+            "cdn.example.com",
+            "/assets/a.css",
+            "default",
+            "/assets/a.css",
+        ),
+    ];
+
+    for (method, host, target, expected_cluster, expected_target) in tests {
+        let raw_request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n\r\n", method, target, host);
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let (cluster, target) = table.route(&request).unwrap();
+        assert_eq!(
+            cluster.name, expected_cluster,
+            "{} {}{}",
+            method, host, target
+        );
+        assert_eq!(target, expected_target, "{} {}{}", method, host, target);
+    }
+
+    let connect = "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n";
+    let request = HttpRequest::from_stream(&mut connect.as_bytes()).unwrap();
+    assert!(table.route(&request).is_none());
+}
+
+#[test]
+fn test_remove_dot_segments() {
+    let tests = vec![
+        ("/", "/"),
+        ("/users/1", "/users/1"),
+        ("/api/../internal", "/internal"),
+        ("/api/%2e%2e/internal", "/internal"),
+        ("/api/%2E./internal", "/internal"),
+        ("/a/./b/../c/", "/a/c/"),
+        ("/a/b/..", "/a/"),
+        ("/a/.", "/a/"),
+        ("/../../etc", "/etc"),
+        ("/a/..b/c", "/a/..b/c"),
+        ("*", "*"),
+    ];
+    for (path, expected) in tests {
+        assert_eq!(remove_dot_segments(path), expected, "{}", path);
+    }
+}
+
+#[test]
+fn test_route_resolves_dot_segments() {
+    let table = RoutingTable::new(
+        vec![
+            Route {
+                rewrite: Some(String::new()),
+                ..route(None, PathMatch::Prefix("/api".to_string()), &[], "api")
+            },
+            route(None, PathMatch::Prefix("/".to_string()), &[], "default"),
+        ],
+        vec![
+            cluster("api", "api:8080"),
+            cluster("default", "default:8080"),
+        ],
+    )
+    .unwrap();
+
+    let tests = vec![
+        ("/api/../internal", "default", "/internal"),
+        ("/api/%2e%2e/internal?a=1", "default", "/internal?a=1"),
+        ("/api/users/../orders", "api", "/orders"),
+        ("/internal/../api/users", "api", "/users"),
+    ];
+    for (target, expected_cluster, expected_target) in tests {
+        let raw_request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target);
+        let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+        let (cluster, routed) = table.route(&request).unwrap();
+        assert_eq!(cluster.name, expected_cluster, "{}", target);
+        assert_eq!(routed, expected_target, "{}", target);
+    }
+}
+
+#[test]
+fn test_routing_table_errors() {
+    let users = cluster("users", "users:8080");
+    assert!(RoutingTable::new(
+        vec![route(None, PathMatch::Any, &[], "orders")],
+        vec![users.clone()]
+    )
+    .is_err());
+    assert!(RoutingTable::new(vec![], vec![users.clone(), users]).is_err());
+    assert!(PathMatch::regex("/users/(").is_err());
+}
+
+#[test]
+fn test_inherit_health() {
+    let probed = |addresses: &[&str]| {
+        let endpoints = addresses.iter().map(|a| Endpoint::new(*a, 1)).collect();
+        Cluster::new("users", Policy::RoundRobin, endpoints)
+            .unwrap()
+            .with_health_check(HealthCheck::default())
+    };
+    let table = |cluster: Cluster| RoutingTable::new(vec![], vec![cluster]).unwrap();
+
+    let previous = table(probed(&["a:80", "b:80"]));
+    previous.clusters()[0].balancer.endpoints()[0].set_healthy(false);
+
+    let reloaded = table(probed(&["b:80", "a:80", "c:80"]));
+    reloaded.inherit_health(&previous);
+    let healthy: Vec<bool> = reloaded.clusters()[0]
+        .balancer
+        .endpoints()
+        .iter()
+        .map(|e| e.is_healthy())
+        .collect();
+    assert_eq!(healthy, vec![true, false, true]);
+    // the counter goes on where it was, a reload is not a change
+    let changes: Vec<u64> = reloaded.clusters()[0]
+        .balancer
+        .endpoints()
+        .iter()
+        .map(|e| e.health_changes())
+        .collect();
+    assert_eq!(changes, vec![0, 1, 0]);
+
+    // nothing would ever bring a cluster that is no longer probed back
+    let unprobed = table(cluster("users", "a:80"));
+    unprobed.inherit_health(&previous);
+    assert!(unprobed.clusters()[0].balancer.endpoints()[0].is_healthy());
+}
@@ -0,0 +1,1228 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{mpsc, watch, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    admin::admin_response,
+    balancer::InFlight,
+    body::{copy_body, BodyReader},
+    config::Config,
+    error::ProxyError,
+    forwarding::strip_hop_by_hop,
+    header_map::HeaderMap,
+    health::spawn_health_checks,
+    http_client::{ClientConfig, HTTPClient},
+    http_method::Method,
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_version::Version,
+    reload::{merge_reloaded, watch_config, POLL_INTERVAL},
+    routing::Cluster,
+    shutdown::{drain, shutdown_signal},
+    status_code::StatusCode,
+    tunnel::tunnel,
+    utils::{
+        parse_url, read_request_head_async, response_body_framing, write_to_stream_async,
+        BodyFraming,
+    },
+};
+
+// the config in effect and the client built from it. a request holds on to the one
+// it started with, so a reload never changes a request halfway through
+struct Active {
+    config: Config,
+    client: Arc<HTTPClient>,
+}
+
+// state shared by every connection task
+struct ServerContext {
+    // replaced as a whole on reload
+    active: watch::Sender<Arc<Active>>,
+    // flips to true once shutdown starts, health checks fail from then on
+    // and idle keep-alive connections are closed
+    draining: watch::Sender<bool>,
+    // probes of the endpoints of the active config
+    health_checks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl ServerContext {
+    fn active(&self) -> Arc<Active> {
+        Arc::clone(&self.active.borrow())
+    }
+
+    // swaps in a reloaded config for requests that start from now on
+    fn reload(&self, config: Config) {
+        let current = self.active();
+        let config = merge_reloaded(&current.config, config);
+        // a new client would start with an empty pool, keep it unless it has to change
+        let client = if client_config_changed(&current.config, &config) {
+            Arc::new(build_client(&config))
+        } else {
+            Arc::clone(&current.client)
+        };
+        if let (Some(routing), Some(current_routing)) = (&config.routing, &current.config.routing) {
+            routing.inherit_health(current_routing);
+        }
+        self.active
+            .send_replace(Arc::new(Active { config, client }));
+        self.restart_health_checks();
+        log::info!("config reloaded");
+    }
+
+    // probes the endpoints of the active config instead of whatever was probed before
+    fn restart_health_checks(&self) {
+        let handles = match &self.active().config.routing {
+            Some(routing) => spawn_health_checks(routing),
+            None => Vec::new(),
+        };
+        let mut health_checks = self.health_checks.lock().unwrap_or_else(|e| e.into_inner());
+        for handle in std::mem::replace(&mut *health_checks, handles) {
+            handle.abort();
+        }
+    }
+}
+
+fn build_client(config: &Config) -> HTTPClient {
+    HTTPClient::with_config(
+        config.default_headers.clone(),
+        ClientConfig {
+            pool: config.pool.clone(),
+            timeouts: config.timeouts,
+            retry: config.retry.clone(),
+        },
+    )
+}
+
+fn client_config_changed(current: &Config, new: &Config) -> bool {
+    current.default_headers != new.default_headers
+        || current.pool != new.pool
+        || current.timeouts != new.timeouts
+        || current.retry != new.retry
+}
+
+// answer for a request that failed, the body says what went wrong
+fn error_response(error: &ProxyError) -> HttpResponse {
+    let status_code = error.status_code();
+    let body = format!("{}: {}\n", status_code.to_reason_phrase(), error);
+    text_response(status_code, &body)
+}
+
+pub fn text_response(status_code: StatusCode, body: &str) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        reason: None,
+        version: Version::Http11,
+        headers: HeaderMap::from([("Content-Length".to_string(), body.len().to_string())]),
+        body: body.as_bytes().to_vec(),
+    }
+}
+
+// function to listen incoming tcp connections on port until SIGINT or SIGTERM
+pub async fn listen(config: &Config) -> Result<(), ProxyError> {
+    serve(config, shutdown_signal()).await
+}
+
+// like listen, but reloads the config from config_path whenever the file changes
+// or the process gets SIGHUP
+pub async fn listen_with_reload(config: &Config, config_path: PathBuf) -> Result<(), ProxyError> {
+    let reloads = watch_config(config_path, POLL_INTERVAL);
+    serve_with_reloads(config, shutdown_signal(), Some(reloads)).await
+}
+
+// every connection runs in its own task, once max_connections are in flight
+// new connections are answered with 503 right away. admin connections don't count
+// against the limit, so health checks keep working under load.
+// when shutdown resolves the listeners are closed and in-flight connections get
+// the shutdown_timeout in effect to finish before this returns
+pub async fn serve<F: Future<Output = ()>>(config: &Config, shutdown: F) -> Result<(), ProxyError> {
+    serve_with_reloads(config, shutdown, None).await
+}
+
+// serves like serve and switches to every config received on reloads. requests in
+// flight finish with the config they started with
+pub async fn serve_with_reloads<F: Future<Output = ()>>(
+    config: &Config,
+    shutdown: F,
+    reloads: Option<mpsc::Receiver<Config>>,
+) -> Result<(), ProxyError> {
+    let listener = bind(&config.address, &config.port, config.accept_backlog).await?;
+    log::info!(
+        "Listening on port {} with up to {} concurrent connections",
+        config.port,
+        config.max_connections
+    );
+    let admin_listener = match &config.admin_port {
+        Some(port) => {
+            let admin_listener = bind(&config.admin_address, port, config.accept_backlog).await?;
+            log::info!("Admin endpoints on {}:{}", config.admin_address, port);
+            Some(admin_listener)
+        }
+        None => None,
+    };
+    serve_listeners(listener, admin_listener, config, shutdown, reloads).await
+}
+
+// listens on the first address host resolves to. up to backlog connections wait in
+// the OS queue while all max_connections are busy or the accept loop is behind
+async fn bind(host: &str, port: &str, backlog: usize) -> Result<TcpListener, ProxyError> {
+    let address = tokio::net::lookup_host(format!("{}:{}", host, port))
+        .await?
+        .next()
+        .ok_or_else(|| ProxyError::Dns(format!("{} did not resolve", host)))?;
+    let socket = if address.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    // like TcpListener::bind, so a restarted proxy can take over the port right away
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    Ok(socket.listen(u32::try_from(backlog).unwrap_or(u32::MAX))?)
+}
+
+// serves like serve_with_reloads on listeners that are already bound, the addresses
+// and ports in config are not looked at
+pub async fn serve_listeners<F: Future<Output = ()>>(
+    listener: TcpListener,
+    admin_listener: Option<TcpListener>,
+    config: &Config,
+    shutdown: F,
+    mut reloads: Option<mpsc::Receiver<Config>>,
+) -> Result<(), ProxyError> {
+    let context = Arc::new(ServerContext {
+        active: watch::channel(Arc::new(Active {
+            config: config.clone(),
+            client: Arc::new(build_client(config)),
+        }))
+        .0,
+        draining: watch::channel(false).0,
+        health_checks: Mutex::new(Vec::new()),
+    });
+    context.restart_health_checks();
+    let connection_slots = Arc::new(Semaphore::new(config.max_connections));
+    let mut shutdown = pin!(shutdown);
+
+    loop {
+        let accepted = tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = listener.accept() => accepted,
+            Some(config) = next_reload(&mut reloads) => {
+                context.reload(config);
+                continue;
+            }
+            accepted = accept_admin(&admin_listener) => {
+                spawn_admin_connection(&context, accepted);
+                continue;
+            }
+        };
+        match accepted {
+            Ok((socket, addr)) => {
+                log::info!("incoming request from: {:?}", addr);
+                if !context.active().config.acl.allows_client(addr.ip()) {
+                    log::warn!("rejecting connection from {:?}, not allowed by acl", addr);
+                    let mut response = text_response(StatusCode::Forbidden, "Forbidden\n");
+                    set_connection_header(&mut response, false);
+                    tokio::spawn(reject_connection(socket, response));
+                    continue;
+                }
+                let context = Arc::clone(&context);
+                match Arc::clone(&connection_slots).try_acquire_owned() {
+                    Ok(permit) => {
+                        tokio::spawn(async move {
+                            handle_connection(&context, socket, addr, false).await;
+                            drop(permit);
+                        });
+                    }
+                    Err(_) => {
+                        log::warn!(
+                            "connection limit reached, rejecting connection from {:?}",
+                            addr
+                        );
+                        tokio::spawn(overloaded_handler(socket));
+                    }
+                }
+            }
+            Err(e) => {
+                log::error!("failed to accept connection: {:?}", e);
+            }
+        }
+    }
+
+    log::info!("shutting down, no longer accepting connections");
+    context.draining.send_replace(true);
+    for handle in context
+        .health_checks
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .drain(..)
+    {
+        handle.abort();
+    }
+    drop(listener);
+    // the admin listener stays up until draining is over, so load balancers get a
+    // failing readiness check instead of a refused connection
+    // shutdown_timeout may have been reloaded, max_connections only changes on restart
+    let drained = drain(
+        &connection_slots,
+        config.max_connections,
+        context.active().config.shutdown_timeout,
+    );
+    let mut drained = pin!(drained);
+    loop {
+        tokio::select! {
+            _ = &mut drained => break,
+            accepted = accept_admin(&admin_listener) => spawn_admin_connection(&context, accepted),
+        }
+    }
+    drop(admin_listener);
+    log::info!(
+        "upstream pool stats: {:?}",
+        context.active().client.pool_stats()
+    );
+    Ok(())
+}
+
+// next config to switch to, never resolves if there is nothing to reload from
+async fn next_reload(reloads: &mut Option<mpsc::Receiver<Config>>) -> Option<Config> {
+    match reloads {
+        Some(reloads) => reloads.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn spawn_admin_connection(
+    context: &Arc<ServerContext>,
+    accepted: std::io::Result<(TcpStream, SocketAddr)>,
+) {
+    match accepted {
+        Ok((socket, addr)) => {
+            let context = Arc::clone(context);
+            tokio::spawn(async move {
+                handle_connection(&context, socket, addr, true).await;
+            });
+        }
+        Err(e) => log::error!("failed to accept admin connection: {:?}", e),
+    }
+}
+
+// next connection on the admin listener, never resolves if there is none
+async fn accept_admin(
+    admin_listener: &Option<TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match admin_listener {
+        Some(admin_listener) => admin_listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn overloaded_handler(socket: TcpStream) {
+    let response = HttpResponse {
+        status_code: StatusCode::ServiceUnavailable,
+        reason: None,
+        version: Version::Http11,
+        headers: HeaderMap::from([
+            ("Content-Length".to_string(), "19".to_string()),
+            ("Retry-After".to_string(), "1".to_string()),
+        ]),
+        body: b"Service Unavailable".to_vec(),
+    };
+    reject_connection(socket, response).await
+}
+
+// answers a connection that won't be served and closes it
+async fn reject_connection(mut socket: TcpStream, response: HttpResponse) {
+    if let Err(e) = write_to_stream_async(&mut socket, &response.serialize()).await {
+        log::error!("failed to write to socket: {:?}", e);
+    }
+    close_socket(socket).await;
+}
+
+// serves requests on the connection until either side asks to close it.
+// requests are answered one at a time in the order they were read, so pipelined
+// requests waiting in the buffer get their responses in the same order.
+// requests on an admin connection are answered by the proxy itself, those stay
+// open while draining
+async fn handle_connection(
+    context: &ServerContext,
+    socket: TcpStream,
+    addr: SocketAddr,
+    admin: bool,
+) {
+    let mut socket = BufReader::new(socket);
+    let mut draining = context.draining.subscribe();
+
+    loop {
+        if !wait_for_request(
+            &mut socket,
+            &mut draining,
+            !admin,
+            context.active().config.keep_alive_timeout,
+        )
+        .await
+        {
+            break;
+        }
+
+        let (mut request, request_framing) = match read_request_head_async(&mut socket).await {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!("failed to read from stream: {:?}", e);
+                // the rest of the stream can't be trusted, answer and close
+                let mut response = error_response(&e);
+                set_connection_header(&mut response, false);
+                if let Err(e) = write_to_stream_async(&mut socket, &response.serialize()).await {
+                    log::error!("failed to write to socket: {:?}", e);
+                }
+                break;
+            }
+        };
+
+        // the request is served with the config in effect when it arrived
+        let active = context.active();
+        // requests the proxy answers itself instead of forwarding them.
+        // a routed request counts as in flight on its endpoint until the response is written
+        let mut endpoint = None;
+        let mut cluster = None;
+        let local_response = if admin {
+            Some(admin_response(&request, &active.config, *draining.borrow()))
+        } else {
+            match route_request(&active.config, &mut request) {
+                Ok(picked) => {
+                    if let Some((picked_cluster, picked)) = picked {
+                        cluster = Some(picked_cluster);
+                        endpoint = Some(picked);
+                    }
+                    None
+                }
+                Err(response) => Some(response),
+            }
+        };
+
+        // CONNECT is not routed in reverse proxy mode, those were answered above
+        if request.method == Method::Connect && local_response.is_none() {
+            tunnel(
+                &mut socket,
+                &request,
+                &active.config.connect_ports,
+                active.config.timeouts.connect,
+            )
+            .await;
+            break;
+        }
+
+        let client_keep_alive = request.keep_alive();
+
+        // bodies are streamed between client and upstream, neither is held in memory
+        let mut request_body = BodyReader::new(&mut socket, request_framing);
+        let mut upstream = None;
+        let mut response = if let Some(response) = local_response {
+            // skip the body so the next request can be read
+            if let Err(e) = copy_body(&mut request_body, &mut tokio::io::sink(), false).await {
+                log::error!("failed to read request body: {:?}", e);
+            }
+            response
+        } else {
+            let forwarding = &active.config.forwarding;
+            forwarding.add_request_headers(&mut request.headers, addr.ip(), "http");
+            let timeouts = active.client.timeouts();
+            // a request that could not be connected moves on to an endpoint of its
+            // cluster it was not sent to yet
+            let mut tried: Vec<String> = endpoint
+                .iter()
+                .map(|endpoint: &InFlight| endpoint.address.clone())
+                .collect();
+            let reroute = || {
+                let picked = cluster?.balancer.pick_excluding(&request, &tried)?;
+                let address = picked.address.clone();
+                tried.push(address.clone());
+                endpoint = Some(picked);
+                Some(address)
+            };
+            match active
+                .client
+                .send_rerouting_async(&request, &mut request_body, timeouts, reroute)
+                .await
+            {
+                Ok(response_stream) => {
+                    let mut response = response_stream.response.clone();
+                    strip_hop_by_hop(&mut response.headers);
+                    forwarding.add_response_headers(&mut response.headers);
+                    upstream = Some(response_stream);
+                    response
+                }
+                Err(e) => {
+                    log::error!("failed to execute request: {:?}", e);
+                    error_response(&e)
+                }
+            }
+        };
+        // the next request can only be found if this one was read to the end
+        let request_complete = request_body.is_done();
+
+        // HTTP/1.0 clients can't decode chunked bodies, those are sent de-chunked and
+        // delimited by closing the connection
+        let chunked =
+            matches!(&upstream, Some(upstream) if upstream.framing == BodyFraming::Chunked);
+        let chunked = chunked && request.version == Version::Http11;
+        if request.version == Version::Http10 {
+            response.headers.remove("Transfer-Encoding");
+        }
+
+        // proxy connections are not reused once draining started
+        // a body delimited by closing the connection can only be forwarded the same way
+        let close_delimited = response_body_framing(
+            response.status_code.to_u32(),
+            &request.method,
+            &response.headers,
+        )
+        .map(|framing| framing == BodyFraming::CloseDelimited)
+        .unwrap_or(true);
+        let mut keep_alive = client_keep_alive
+            && request_complete
+            && !close_delimited
+            && (admin || !*draining.borrow());
+        set_connection_header(&mut response, keep_alive);
+        let written = match upstream {
+            Some(mut upstream) => {
+                let written =
+                    match write_to_stream_async(&mut socket, &response.serialize_head()).await {
+                        Ok(()) => copy_body(&mut upstream.body, &mut socket, chunked)
+                            .await
+                            .map(|_| ()),
+                        Err(e) => Err(e),
+                    };
+                upstream.finish();
+                written
+            }
+            None => write_to_stream_async(&mut socket, &response.serialize()).await,
+        };
+        drop(endpoint);
+        if let Err(e) = written {
+            log::error!("failed to write to socket: {:?}", e);
+            keep_alive = false;
+        }
+        if !keep_alive {
+            break;
+        }
+    }
+
+    close_socket(socket.into_inner()).await
+}
+
+// checks the request against the acl and, in reverse proxy mode, points it at the
+// endpoint the balancer of its route's cluster picked. Err is the answer for a
+// request that won't be forwarded
+fn route_request<'a>(
+    config: &'a Config,
+    request: &mut HttpRequest,
+) -> Result<Option<(&'a Cluster, InFlight)>, HttpResponse> {
+    let host = request.url.host_str().unwrap_or_default();
+    if !config.acl.allows_host(host) {
+        log::warn!("refusing request to {}, not allowed by acl", host);
+        return Err(text_response(StatusCode::Forbidden, "Forbidden\n"));
+    }
+
+    let routing = match &config.routing {
+        Some(routing) => routing,
+        None => return Ok(None),
+    };
+    let (cluster, target) = match routing.route(request) {
+        Some(route) => route,
+        None => {
+            log::info!("no route for {} {}", request.method, request.url);
+            return Err(text_response(StatusCode::NotFound, "Not Found\n"));
+        }
+    };
+    let endpoint = match cluster.balancer.pick(request) {
+        Some(endpoint) => endpoint,
+        None => {
+            log::warn!("no healthy endpoint in cluster {}", cluster.name);
+            return Err(text_response(
+                StatusCode::ServiceUnavailable,
+                "No Healthy Upstream\n",
+            ));
+        }
+    };
+    log::debug!(
+        "routing {} {} to {} at {}{}",
+        request.method,
+        request.url,
+        cluster.name,
+        endpoint.address,
+        target
+    );
+    request.url = parse_url(&format!("http://{}{}", endpoint.address, target))
+        .map_err(|e| error_response(&e))?;
+    request.target = Some(target);
+    Ok(Some((cluster, endpoint)))
+}
+
+// waits until the next request starts arriving. false when the client closed the
+// connection, it stayed idle past the keep-alive timeout or, with close_on_drain,
+// the server is draining
+async fn wait_for_request(
+    socket: &mut BufReader<TcpStream>,
+    draining: &mut watch::Receiver<bool>,
+    close_on_drain: bool,
+    idle_timeout: Duration,
+) -> bool {
+    tokio::select! {
+        biased;
+        result = socket.fill_buf() => matches!(result, Ok(buffer) if !buffer.is_empty()),
+        _ = tokio::time::sleep(idle_timeout) => false,
+        _ = draining.wait_for(|draining| *draining), if close_on_drain => false,
+    }
+}
+
+// the Connection header describes the hop to the client, whatever upstream sent is replaced
+fn set_connection_header(response: &mut HttpResponse, keep_alive: bool) {
+    let value = if keep_alive { "keep-alive" } else { "close" };
+    response.headers.insert("Connection", value);
+}
+
+async fn close_socket(mut socket: TcpStream) {
+    let res = socket.shutdown().await;
+    match res {
+        Ok(_num_bytes) => {
+            log::debug!("successfully closed socket");
+        }
+        Err(_e) => {
+            log::error!("failed to close socket {:?}", _e);
+        }
+    }
+}
+
+// a server on ports the OS picked, both listeners accept connections from the start
+#[cfg(test)]
+struct TestServer {
+    address: SocketAddr,
+    admin_address: SocketAddr,
+    handle: JoinHandle<Result<(), ProxyError>>,
+}
+
+// serves config until shutdown resolves, the admin listener is always there
+#[cfg(test)]
+async fn spawn_server<F: Future<Output = ()> + Send + 'static>(
+    config: Config,
+    shutdown: F,
+    reloads: Option<mpsc::Receiver<Config>>,
+) -> TestServer {
+    let listener = bind("127.0.0.1", "0", config.accept_backlog).await.unwrap();
+    let admin_listener = bind("127.0.0.1", "0", config.accept_backlog).await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let admin_address = admin_listener.local_addr().unwrap();
+    let handle = tokio::spawn(async move {
+        serve_listeners(listener, Some(admin_listener), &config, shutdown, reloads).await
+    });
+    TestServer {
+        address,
+        admin_address,
+        handle,
+    }
+}
+
+#[cfg(test)]
+async fn spawn_test_server(config: Config) -> TestServer {
+    spawn_server(config, std::future::pending(), None).await
+}
+
+// tests
+#[tokio::test]
+async fn test_listen() {
+    let server = spawn_test_server(Config::default()).await;
+    let client = HTTPClient::new(HeaderMap::new());
+
+    let request = crate::http_request::HttpRequest {
+        method: crate::http_method::Method::Get,
+        version: crate::http_version::Version::Http11,
+        body: "".into(),
+        url: url::Url::parse(&format!("http://{}/healthz", server.admin_address)).unwrap(),
+        target: None,
+        headers: HeaderMap::from([("Host".to_string(), server.admin_address.to_string())]),
+    };
+
+    let response = client.execute_async(request).await.unwrap();
+    assert_eq!(response.body_text().unwrap().trim(), "OK");
+}
+
+#[tokio::test]
+async fn test_listen_rejects_over_limit() {
+    use tokio::io::AsyncReadExt;
+
+    let server = spawn_test_server(Config {
+        max_connections: 1,
+        ..Config::default()
+    })
+    .await;
+    // the first connection holds the only slot without sending a request
+    let _held = TcpStream::connect(server.address).await.unwrap();
+
+    let mut rejected = TcpStream::connect(server.address).await.unwrap();
+    let mut response = String::new();
+    rejected.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+}
+
+#[tokio::test]
+async fn test_serve_drains_in_flight_requests() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // upstream that takes a while to answer
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+            .await
+            .unwrap();
+    });
+
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+    let server = spawn_server(
+        Config::default(),
+        async {
+            let _ = shutdown_receiver.await;
+        },
+        None,
+    )
+    .await;
+
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+        upstream_port, upstream_port
+    );
+    socket.write_all(request.as_bytes()).await.unwrap();
+
+    // shut down while the upstream is still working on the response
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    shutdown_sender.send(()).unwrap();
+
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("slow"));
+
+    server.handle.await.unwrap().unwrap();
+    assert!(TcpStream::connect(server.address).await.is_err());
+}
+
+#[tokio::test]
+async fn test_keep_alive_pipelined_requests() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = spawn_test_server(Config::default()).await;
+    let mut socket = TcpStream::connect(server.admin_address).await.unwrap();
+
+    // two pipelined requests, the second one asks to close the connection
+    socket
+        .write_all(
+            b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n\
+              GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    let mut responses = String::new();
+    socket.read_to_string(&mut responses).await.unwrap();
+    assert_eq!(responses.matches("HTTP/1.1 200 OK").count(), 2);
+    assert_eq!(responses.matches("Connection: keep-alive").count(), 1);
+    assert!(responses.ends_with("Content-Length: 2\r\nConnection: close\r\n\r\nOK"));
+}
+
+#[tokio::test]
+async fn test_http10_closes_by_default() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = spawn_test_server(Config::default()).await;
+    let mut socket = TcpStream::connect(server.admin_address).await.unwrap();
+
+    socket
+        .write_all(b"GET /healthz HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+
+    // read_to_string only returns once the proxy closed the connection
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.contains("Connection: close"));
+}
+
+#[tokio::test]
+async fn test_streams_response_body() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // upstream that only sends the second half of the body once the client
+    // received the first half, a buffering proxy would never answer
+    let (received_sender, received_receiver) = tokio::sync::oneshot::channel::<()>();
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+            .await
+            .unwrap();
+        received_receiver.await.unwrap();
+        socket.write_all(b"5\r\nworld\r\n0\r\n\r\n").await.unwrap();
+    });
+
+    let server = spawn_test_server(Config::default()).await;
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\n\r\n",
+        upstream_port, upstream_port
+    );
+    socket.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = Vec::new();
+    let mut buffer = [0; 1024];
+    while !String::from_utf8_lossy(&response).contains("hello") {
+        let num_bytes =
+            tokio::time::timeout(std::time::Duration::from_secs(5), socket.read(&mut buffer))
+                .await
+                .expect("first half of the body was not forwarded")
+                .unwrap();
+        assert!(num_bytes > 0);
+        response.extend_from_slice(&buffer[..num_bytes]);
+    }
+    received_sender.send(()).unwrap();
+
+    socket.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.ends_with("\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n"));
+}
+
+#[tokio::test]
+async fn test_dechunks_response_for_http10_client() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\nworld\r\n0\r\n\r\n")
+            .await
+            .unwrap();
+    });
+
+    let server = spawn_test_server(Config::default()).await;
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{}/ HTTP/1.0\r\nHost: 127.0.0.1:{}\r\nConnection: keep-alive\r\n\r\n",
+        upstream_port, upstream_port
+    );
+    socket.write_all(request.as_bytes()).await.unwrap();
+
+    // read_to_string only returns once the proxy closed the connection
+    let mut response = String::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        socket.read_to_string(&mut response),
+    )
+    .await
+    .expect("connection was not closed after the body")
+    .unwrap();
+    assert!(response.contains("Connection: close"));
+    assert!(!response.contains("Transfer-Encoding"));
+    assert!(response.ends_with("\r\n\r\nhelloworld"));
+}
+
+#[tokio::test]
+async fn test_errors_map_to_status_codes() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = spawn_test_server(Config::default()).await;
+
+    // nothing listens on the port of a listener that was closed again
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_port = closed.local_addr().unwrap().port();
+    drop(closed);
+
+    let tests = vec![
+        (
+            "NOT A REQUEST\r\n\r\n".to_string(),
+            "HTTP/1.1 400 ",
+            "invalid request",
+        ),
+        (
+            format!(
+                "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n\r\n",
+                closed_port
+            ),
+            "HTTP/1.1 502 Bad Gateway",
+            "connect failed",
+        ),
+    ];
+
+    for (request, expected_status, expected_body) in tests {
+        let mut socket = TcpStream::connect(server.address).await.unwrap();
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with(expected_status), "{}", response);
+        assert!(response.contains(expected_body), "{}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_silent_upstream_times_out() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // upstream that accepts the connection but never answers
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (_socket, _) = upstream.accept().await.unwrap();
+        std::future::pending::<()>().await;
+    });
+
+    let server = spawn_test_server(Config {
+        timeouts: crate::timeout::Timeouts {
+            first_byte: Duration::from_millis(100),
+            ..Default::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\n\r\n",
+        upstream_port, upstream_port
+    );
+    socket.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(5), socket.read_to_string(&mut response))
+        .await
+        .expect("proxy kept waiting for the upstream")
+        .unwrap();
+    assert!(
+        response.starts_with("HTTP/1.1 504 Gateway Timeout"),
+        "{}",
+        response
+    );
+    assert!(response.contains("timed out"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_health_paths_are_proxied() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 8\r\n\r\nupstream")
+            .await
+            .unwrap();
+    });
+
+    let server = spawn_test_server(Config::default()).await;
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{}/v1/healthcare HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\n\r\n",
+        upstream_port, upstream_port
+    );
+    socket.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.ends_with("upstream"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_acl_denied_host_is_forbidden() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let server = spawn_test_server(Config {
+        acl: crate::acl::Acl {
+            deny_hosts: vec!["*.internal".to_string()],
+            ..Default::default()
+        },
+        ..Config::default()
+    })
+    .await;
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+    socket
+        .write_all(
+            b"GET http://db.internal/ HTTP/1.1\r\nHost: db.internal\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_reload_applies_to_new_requests() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (reload_sender, reloads) = mpsc::channel(1);
+    let server = spawn_server(Config::default(), std::future::pending(), Some(reloads)).await;
+    let mut socket = TcpStream::connect(server.address).await.unwrap();
+
+    reload_sender
+        .send(Config {
+            acl: crate::acl::Acl {
+                deny_hosts: vec!["*.internal".to_string()],
+                ..Default::default()
+            },
+            ..Config::default()
+        })
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // the connection was accepted before the reload, its next request still gets the new config
+    socket
+        .write_all(
+            b"GET http://db.internal/ HTTP/1.1\r\nHost: db.internal\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    socket.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+}
+
+#[tokio::test]
+async fn test_reverse_proxy_routes() {
+    use crate::{
+        balancer::{Endpoint, Policy},
+        routing::{Cluster, PathMatch, Route, RoutingTable},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // answers with the request line it got
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let read = socket.read(&mut buffer).await.unwrap();
+            let head = String::from_utf8_lossy(&buffer[..read]).to_string();
+            let request_line = head.lines().next().unwrap_or_default().to_string();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                request_line.len(),
+                request_line
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        }
+    });
+
+    let routing = RoutingTable::new(
+        vec![Route {
+            host: Some("api.example.com".to_string()),
+            path: PathMatch::Prefix("/users".to_string()),
+            methods: vec![],
+            cluster: "users".to_string(),
+            rewrite: Some("/v1".to_string()),
+        }],
+        vec![Cluster::new(
+            "users",
+            Policy::RoundRobin,
+            vec![Endpoint::new(format!("127.0.0.1:{}", upstream_port), 1)],
+        )
+        .unwrap()],
+    )
+    .unwrap();
+    let server = spawn_test_server(Config {
+        routing: Some(routing),
+        ..Config::default()
+    })
+    .await;
+
+    let tests = vec![
+        ("api.example.com", "/users/1?x=1", "GET /v1/1?x=1 HTTP/1.1"),
+        ("api.example.com", "/orders", "Not Found\n"),
+        ("www.example.com", "/users/1", "Not Found\n"),
+    ];
+    for (host, target, expected) in tests {
+        let mut socket = TcpStream::connect(server.address).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            target, host
+        );
+        socket.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(
+            response.ends_with(expected),
+            "{}{}: {}",
+            host,
+            target,
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_reverse_proxy_repicks_after_connect_error() {
+    use crate::{
+        balancer::{Endpoint, Policy},
+        routing::{PathMatch, Route, RoutingTable},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        loop {
+            let (mut socket, _) = upstream.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = socket.read(&mut buffer).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nup")
+                .await
+                .unwrap();
+        }
+    });
+    // nothing listens on the port of a listener that was closed again
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let closed_port = closed.local_addr().unwrap().port();
+    drop(closed);
+
+    let routing = RoutingTable::new(
+        vec![Route {
+            host: None,
+            path: PathMatch::Prefix("/".to_string()),
+            methods: vec![],
+            cluster: "app".to_string(),
+            rewrite: None,
+        }],
+        vec![Cluster::new(
+            "app",
+            Policy::RoundRobin,
+            vec![
+                Endpoint::new(format!("127.0.0.1:{}", closed_port), 1),
+                Endpoint::new(format!("127.0.0.1:{}", upstream_port), 1),
+            ],
+        )
+        .unwrap()],
+    )
+    .unwrap();
+    let server = spawn_test_server(Config {
+        routing: Some(routing),
+        ..Config::default()
+    })
+    .await;
+
+    // every other request is first sent to the endpoint that is down
+    for _ in 0..4 {
+        let mut socket = TcpStream::connect(server.address).await.unwrap();
+        socket
+            .write_all(b"GET / HTTP/1.1\r\nHost: app.example.com\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+}
+
+#[tokio::test]
+async fn test_readiness_fails_while_draining() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // upstream that holds the request until it is told to answer, keeping the proxy draining
+    let (answer_sender, answer_receiver) = tokio::sync::oneshot::channel::<()>();
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let mut buffer = [0; 1024];
+        let _ = socket.read(&mut buffer).await.unwrap();
+        answer_receiver.await.unwrap();
+        socket
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+            .await
+            .unwrap();
+    });
+
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::oneshot::channel::<()>();
+    let server = spawn_server(
+        Config::default(),
+        async {
+            let _ = shutdown_receiver.await;
+        },
+        None,
+    )
+    .await;
+    // a load balancer's keep-alive connection, opened before shutdown
+    let mut health_check = TcpStream::connect(server.admin_address).await.unwrap();
+    let mut in_flight = TcpStream::connect(server.address).await.unwrap();
+    let request = format!(
+        "GET http://127.0.0.1:{}/ HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\n",
+        upstream_port, upstream_port
+    );
+    in_flight.write_all(request.as_bytes()).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    shutdown_sender.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // new proxy connections are refused, health checks are still answered
+    assert!(TcpStream::connect(server.address).await.is_err());
+    let tests = vec![
+        ("/readyz", "HTTP/1.1 503 Service Unavailable", "Draining"),
+        ("/healthz", "HTTP/1.1 200 OK", "OK"),
+    ];
+    for (path, expected_status, expected_body) in tests {
+        let mut socket = TcpStream::connect(server.admin_address).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with(expected_status), "{}", response);
+        assert!(response.ends_with(expected_body), "{}", response);
+    }
+    health_check
+        .write_all(b"GET /readyz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    health_check.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+
+    // once the last request is done the admin listener goes away as well
+    answer_sender.send(()).unwrap();
+    let mut response = String::new();
+    in_flight.read_to_string(&mut response).await.unwrap();
+    assert!(response.ends_with("slow"), "{}", response);
+    server.handle.await.unwrap().unwrap();
+    assert!(TcpStream::connect(server.admin_address).await.is_err());
+}
@@ -0,0 +1,118 @@
+use std::time::Duration;
+
+use tokio::sync::Semaphore;
+
+// resolves once the process receives SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                log::error!("failed to listen for SIGINT: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+            log::info!("received SIGINT");
+        }
+        _ = terminate() => {
+            log::info!("received SIGTERM");
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(e) => {
+            log::error!("failed to listen for SIGTERM: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending::<()>().await;
+}
+
+// outcome of draining connections on shutdown
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrainReport {
+    // connections in flight when draining started
+    pub active: usize,
+    // connections that finished before the deadline
+    pub drained: usize,
+}
+
+// waits until every connection slot is returned or the deadline passes.
+// each in-flight connection holds one permit of connection_slots
+pub async fn drain(
+    connection_slots: &Semaphore,
+    max_connections: usize,
+    deadline: Duration,
+) -> DrainReport {
+    let active = max_connections - connection_slots.available_permits();
+    log::info!("draining {} active connections", active);
+
+    let all_slots = u32::try_from(max_connections).unwrap_or(u32::MAX);
+    let drained =
+        match tokio::time::timeout(deadline, connection_slots.acquire_many(all_slots)).await {
+            Ok(_) => active,
+            Err(_) => {
+                let remaining = max_connections - connection_slots.available_permits();
+                active.saturating_sub(remaining)
+            }
+        };
+
+    let report = DrainReport { active, drained };
+    if report.drained == report.active {
+        log::info!("drained {} connections", report.drained);
+    } else {
+        log::warn!(
+            "shutdown deadline reached, drained {} of {} connections",
+            report.drained,
+            report.active
+        );
+    }
+    report
+}
+
+#[tokio::test]
+async fn test_drain() {
+    let connection_slots = std::sync::Arc::new(Semaphore::new(4));
+    let permit = std::sync::Arc::clone(&connection_slots)
+        .try_acquire_owned()
+        .unwrap();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(permit);
+    });
+
+    let report = drain(&connection_slots, 4, Duration::from_secs(5)).await;
+    assert_eq!(
+        report,
+        DrainReport {
+            active: 1,
+            drained: 1
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_drain_deadline() {
+    let connection_slots = Semaphore::new(4);
+    // a connection that never finishes, e.g. a long lived tunnel
+    let _stuck = connection_slots.try_acquire().unwrap();
+
+    let report = drain(&connection_slots, 4, Duration::from_millis(20)).await;
+    assert_eq!(
+        report,
+        DrainReport {
+            active: 1,
+            drained: 0
+        }
+    );
+}
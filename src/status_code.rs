@@ -1,83 +1,255 @@
-/// HTTP status code
-#[derive(Debug, Clone, PartialEq)]
-pub enum StatusCode {
+use crate::error::ProxyError;
+
+// declares StatusCode with the code and reason phrase of every variant,
+// so the three stay in one place
+macro_rules! status_codes {
+    ($($(#[$doc:meta])* $variant:ident = $code:literal, $phrase:literal;)*) => {
+        /// HTTP status code
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum StatusCode {
+            $($(#[$doc])* $variant,)*
+            /// any other code in 100..=599, e.g. an unregistered or newer one
+            Other(u16),
+        }
+
+        impl StatusCode {
+            pub fn from_u32(status_code: u32) -> Result<Self, ProxyError> {
+                match status_code {
+                    $($code => Ok(StatusCode::$variant),)*
+                    100..=599 => Ok(StatusCode::Other(status_code as u16)),
+                    _ => Err(ProxyError::Parse(format!(
+                        "invalid status code {}",
+                        status_code
+                    ))),
+                }
+            }
+
+            // reason phrase from the IANA registry, empty for unknown codes
+            pub fn to_reason_phrase(&self) -> &str {
+                match self {
+                    $(StatusCode::$variant => $phrase,)*
+                    StatusCode::Other(_) => "",
+                }
+            }
+
+            pub fn to_u32(&self) -> u32 {
+                match self {
+                    $(StatusCode::$variant => $code,)*
+                    StatusCode::Other(code) => *code as u32,
+                }
+            }
+        }
+    };
+}
+
+// https://www.iana.org/assignments/http-status-codes
+status_codes! {
+    /// 100 Continue
+    Continue = 100, "Continue";
+    /// 101 Switching Protocols
+    SwitchingProtocols = 101, "Switching Protocols";
+    /// 102 Processing
+    Processing = 102, "Processing";
+    /// 103 Early Hints
+    EarlyHints = 103, "Early Hints";
     /// 200 OK
-    OK = 200,
+    OK = 200, "OK";
+    /// 201 Created
+    Created = 201, "Created";
+    /// 202 Accepted
+    Accepted = 202, "Accepted";
+    /// 203 Non-Authoritative Information
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    /// 204 No Content
+    NoContent = 204, "No Content";
+    /// 205 Reset Content
+    ResetContent = 205, "Reset Content";
+    /// 206 Partial Content
+    PartialContent = 206, "Partial Content";
+    /// 207 Multi-Status
+    MultiStatus = 207, "Multi-Status";
+    /// 208 Already Reported
+    AlreadyReported = 208, "Already Reported";
+    /// 226 IM Used
+    ImUsed = 226, "IM Used";
+    /// 300 Multiple Choices
+    MultipleChoices = 300, "Multiple Choices";
     /// 301 Moved Permanently
-    MovedPermanently = 301,
+    MovedPermanently = 301, "Moved Permanently";
     /// 302 Found
-    Found = 302,
-    /// 404 Not Found
-    NotFound = 404,
+    Found = 302, "Found";
+    /// 303 See Other
+    SeeOther = 303, "See Other";
+    /// 304 Not Modified
+    NotModified = 304, "Not Modified";
+    /// 305 Use Proxy
+    UseProxy = 305, "Use Proxy";
+    /// 307 Temporary Redirect
+    TemporaryRedirect = 307, "Temporary Redirect";
+    /// 308 Permanent Redirect
+    PermanentRedirect = 308, "Permanent Redirect";
     /// 400 Bad Request
-    InvalidRequest = 400,
+    InvalidRequest = 400, "Bad Request";
     /// 401 Unauthorized
-    Unauthorized = 401,
+    Unauthorized = 401, "Unauthorized";
+    /// 402 Payment Required
+    PaymentRequired = 402, "Payment Required";
     /// 403 Forbidden
-    Forbidden = 403,
+    Forbidden = 403, "Forbidden";
+    /// 404 Not Found
+    NotFound = 404, "Not Found";
     /// 405 Method Not Allowed
-    MethodNotAllowed = 405,
+    MethodNotAllowed = 405, "Method Not Allowed";
     /// 406 Not Acceptable
-    NotAcceptable = 406,
+    NotAcceptable = 406, "Not Acceptable";
+    /// 407 Proxy Authentication Required
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    /// 408 Request Timeout
+    RequestTimeout = 408, "Request Timeout";
+    /// 409 Conflict
+    Conflict = 409, "Conflict";
+    /// 410 Gone
+    Gone = 410, "Gone";
+    /// 411 Length Required
+    LengthRequired = 411, "Length Required";
+    /// 412 Precondition Failed
+    PreconditionFailed = 412, "Precondition Failed";
+    /// 413 Content Too Large
+    ContentTooLarge = 413, "Content Too Large";
+    /// 414 URI Too Long
+    UriTooLong = 414, "URI Too Long";
+    /// 415 Unsupported Media Type
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    /// 416 Range Not Satisfiable
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    /// 417 Expectation Failed
+    ExpectationFailed = 417, "Expectation Failed";
+    /// 421 Misdirected Request
+    MisdirectedRequest = 421, "Misdirected Request";
+    /// 422 Unprocessable Content
+    UnprocessableContent = 422, "Unprocessable Content";
+    /// 423 Locked
+    Locked = 423, "Locked";
+    /// 424 Failed Dependency
+    FailedDependency = 424, "Failed Dependency";
+    /// 425 Too Early
+    TooEarly = 425, "Too Early";
+    /// 426 Upgrade Required
+    UpgradeRequired = 426, "Upgrade Required";
+    /// 428 Precondition Required
+    PreconditionRequired = 428, "Precondition Required";
+    /// 429 Too Many Requests
+    TooManyRequests = 429, "Too Many Requests";
+    /// 431 Request Header Fields Too Large
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    /// 451 Unavailable For Legal Reasons
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
     /// 500 Internal Server Error
-    InternalServerError = 500,
+    InternalServerError = 500, "Internal Server Error";
     /// 501 Not Implemented
-    NotImplemented = 501,
+    NotImplemented = 501, "Not Implemented";
     /// 502 Bad Gateway
-    BadGateway = 502,
+    BadGateway = 502, "Bad Gateway";
+    /// 503 Service Unavailable
+    ServiceUnavailable = 503, "Service Unavailable";
+    /// 504 Gateway Timeout
+    GatewayTimeout = 504, "Gateway Timeout";
+    /// 505 HTTP Version Not Supported
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    /// 506 Variant Also Negotiates
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    /// 507 Insufficient Storage
+    InsufficientStorage = 507, "Insufficient Storage";
+    /// 508 Loop Detected
+    LoopDetected = 508, "Loop Detected";
+    /// 510 Not Extended
+    NotExtended = 510, "Not Extended";
+    /// 511 Network Authentication Required
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
 }
 
 impl StatusCode {
-    pub fn from_u32(status_code: u32) -> Result<Self, Box<dyn std::error::Error>> {
-        match status_code {
-            200 => Ok(StatusCode::OK),
-            301 => Ok(StatusCode::MovedPermanently),
-            302 => Ok(StatusCode::Found),
-            400 => Ok(StatusCode::InvalidRequest),
-            404 => Ok(StatusCode::NotFound),
-            401 => Ok(StatusCode::Unauthorized),
-            403 => Ok(StatusCode::Forbidden),
-            405 => Ok(StatusCode::MethodNotAllowed),
-            406 => Ok(StatusCode::NotAcceptable),
-            500 => Ok(StatusCode::InternalServerError),
-            501 => Ok(StatusCode::NotImplemented),
-            502 => Ok(StatusCode::BadGateway),
-            0..=99 | 600..=u32::MAX => Err("invalid status code".into()),
-            _ => Err("unknown status code".into()),
-        }
+    /// 1xx
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.to_u32())
     }
 
-    pub fn to_reason_phrase(&self) -> &str {
-        match self {
-            StatusCode::OK => "OK",
-            StatusCode::MovedPermanently => "Moved Permanently",
-            StatusCode::Found => "Found",
-            StatusCode::InvalidRequest => "Invalid Request",
-            StatusCode::NotFound => "Not Found",
-            StatusCode::Unauthorized => "Unauthorized",
-            StatusCode::Forbidden => "Forbidden",
-            StatusCode::MethodNotAllowed => "Method Not Allowed",
-            StatusCode::NotAcceptable => "Not Acceptable",
-            StatusCode::InternalServerError => "Internal Server Error",
-            StatusCode::NotImplemented => "Not Implemented",
-            StatusCode::BadGateway => "Bad Gateway",
-        }
+    /// 2xx
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.to_u32())
     }
 
-    pub fn to_u32(&self) -> u32 {
-        match self {
-            StatusCode::OK => 200,
-            StatusCode::MovedPermanently => 301,
-            StatusCode::Found => 302,
-            StatusCode::InvalidRequest => 400,
-            StatusCode::NotFound => 404,
-            StatusCode::Unauthorized => 401,
-            StatusCode::Forbidden => 403,
-            StatusCode::MethodNotAllowed => 405,
-            StatusCode::NotAcceptable => 406,
-            StatusCode::InternalServerError => 500,
-            StatusCode::NotImplemented => 501,
-            StatusCode::BadGateway => 502,
+    /// 3xx
+    pub fn is_redirect(&self) -> bool {
+        (300..400).contains(&self.to_u32())
+    }
+
+    /// 4xx
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.to_u32())
+    }
+
+    /// 5xx
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.to_u32())
+    }
+}
+
+#[test]
+fn test_from_u32() {
+    let tests = vec![
+        (200, Some(StatusCode::OK), "OK"),
+        (201, Some(StatusCode::Created), "Created"),
+        (204, Some(StatusCode::NoContent), "No Content"),
+        (304, Some(StatusCode::NotModified), "Not Modified"),
+        (400, Some(StatusCode::InvalidRequest), "Bad Request"),
+        (429, Some(StatusCode::TooManyRequests), "Too Many Requests"),
+        (
+            503,
+            Some(StatusCode::ServiceUnavailable),
+            "Service Unavailable",
+        ),
+        (299, Some(StatusCode::Other(299)), ""),
+        (599, Some(StatusCode::Other(599)), ""),
+        (99, None, ""),
+        (600, None, ""),
+    ];
+
+    for (code, expected, reason_phrase) in tests {
+        let actual = StatusCode::from_u32(code).ok();
+        assert_eq!(actual, expected, "{}", code);
+        if let Some(status_code) = actual {
+            assert_eq!(status_code.to_u32(), code);
+            assert_eq!(status_code.to_reason_phrase(), reason_phrase);
         }
     }
 }
+
+#[test]
+fn test_classes() {
+    let tests = vec![
+        (StatusCode::Continue, [true, false, false, false, false]),
+        (StatusCode::NoContent, [false, true, false, false, false]),
+        (StatusCode::Other(299), [false, true, false, false, false]),
+        (
+            StatusCode::PermanentRedirect,
+            [false, false, true, false, false],
+        ),
+        (StatusCode::NotFound, [false, false, false, true, false]),
+        (
+            StatusCode::GatewayTimeout,
+            [false, false, false, false, true],
+        ),
+    ];
+
+    for (status_code, expected) in tests {
+        let actual = [
+            status_code.is_informational(),
+            status_code.is_success(),
+            status_code.is_redirect(),
+            status_code.is_client_error(),
+            status_code.is_server_error(),
+        ];
+        assert_eq!(actual, expected, "{:?}", status_code);
+    }
+}
@@ -0,0 +1,234 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+use crate::error::ProxyError;
+
+// how long an upstream exchange may take
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    // resolving the host and opening the connection
+    pub connect: Duration,
+    // from sending the request to the first byte of the response
+    pub first_byte: Duration,
+    // between two reads of the response once it started arriving
+    pub idle_read: Duration,
+    // the whole exchange, from connecting to the end of the response body
+    pub total: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            first_byte: Duration::from_secs(60),
+            idle_read: Duration::from_secs(60),
+            total: Duration::from_secs(3600),
+        }
+    }
+}
+
+// runs future, failing with a timeout error once limit passed or deadline was reached
+pub async fn with_deadline<T, F: Future<Output = Result<T, ProxyError>>>(
+    future: F,
+    limit: Duration,
+    deadline: Instant,
+    what: &str,
+) -> Result<T, ProxyError> {
+    let expires = deadline.min(Instant::now() + limit);
+    match tokio::time::timeout_at(expires, future).await {
+        Ok(result) => result,
+        Err(_) => Err(ProxyError::Timeout(expired_message(what, limit, deadline))),
+    }
+}
+
+fn expired_message(what: &str, limit: Duration, deadline: Instant) -> String {
+    if Instant::now() >= deadline {
+        format!("{} did not finish before the total timeout", what)
+    } else {
+        format!("{} took longer than {:?}", what, limit)
+    }
+}
+
+// a connection to an upstream that fails reads with io::ErrorKind::TimedOut once
+// the upstream stays silent for too long, and every operation once deadline passed.
+// the first read may wait first_byte, every later one idle_read
+pub struct TimeoutStream<S> {
+    inner: S,
+    read_limit: Duration,
+    idle_read: Duration,
+    deadline: Instant,
+    // armed while a read is pending
+    read_sleep: Pin<Box<Sleep>>,
+    read_armed: bool,
+    // armed while a write is pending
+    write_sleep: Pin<Box<Sleep>>,
+    write_armed: bool,
+}
+
+impl<S> TimeoutStream<S> {
+    pub fn new(inner: S, timeouts: &Timeouts, deadline: Instant) -> Self {
+        Self {
+            inner,
+            read_limit: timeouts.first_byte,
+            idle_read: timeouts.idle_read,
+            deadline,
+            read_sleep: Box::pin(tokio::time::sleep_until(deadline)),
+            read_armed: false,
+            write_sleep: Box::pin(tokio::time::sleep_until(deadline)),
+            write_armed: false,
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+// polls sleep for an operation that is pending, arming it first if needed
+fn poll_expired(
+    sleep: &mut Pin<Box<Sleep>>,
+    armed: &mut bool,
+    expires: Instant,
+    cx: &mut Context<'_>,
+) -> bool {
+    if !*armed {
+        sleep.as_mut().reset(expires);
+        *armed = true;
+    }
+    sleep.as_mut().poll(cx).is_ready()
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TimeoutStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.read_armed = false;
+                if buf.filled().len() > filled {
+                    this.read_limit = this.idle_read;
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                let expires = this.deadline.min(Instant::now() + this.read_limit);
+                if poll_expired(&mut this.read_sleep, &mut this.read_armed, expires, cx) {
+                    this.read_armed = false;
+                    let message =
+                        expired_message("upstream response", this.read_limit, this.deadline);
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, message)));
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                this.write_armed = false;
+                Poll::Ready(result)
+            }
+            Poll::Pending => {
+                if poll_expired(
+                    &mut this.write_sleep,
+                    &mut this.write_armed,
+                    this.deadline,
+                    cx,
+                ) {
+                    this.write_armed = false;
+                    let message = "upstream request did not finish before the total timeout";
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, message)));
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn test_timeout_stream_first_byte_and_idle() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let timeouts = Timeouts {
+        first_byte: Duration::from_millis(200),
+        idle_read: Duration::from_millis(50),
+        ..Timeouts::default()
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let (client, mut server) = tokio::io::duplex(64);
+    let mut stream = TimeoutStream::new(client, &timeouts, deadline);
+
+    // a slow first byte is fine as long as it is within first_byte
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        server.write_all(b"a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        drop(server);
+    });
+    let mut buffer = [0; 8];
+    assert_eq!(stream.read(&mut buffer).await.unwrap(), 1);
+
+    // after that the same pause exceeds idle_read
+    let error = stream.read(&mut buffer).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+}
+
+#[tokio::test]
+async fn test_timeout_stream_deadline() {
+    use tokio::io::AsyncReadExt;
+
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let (client, _server) = tokio::io::duplex(64);
+    let mut stream = TimeoutStream::new(client, &Timeouts::default(), deadline);
+
+    let mut buffer = [0; 8];
+    let error = stream.read(&mut buffer).await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    assert!(error.to_string().contains("total timeout"));
+
+    let error = with_deadline(
+        async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        },
+        Duration::from_secs(10),
+        deadline,
+        "connecting",
+    )
+    .await
+    .unwrap_err();
+    assert_eq!(
+        error.status_code(),
+        crate::status_code::StatusCode::GatewayTimeout
+    );
+}
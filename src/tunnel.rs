@@ -0,0 +1,171 @@
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::Instant,
+};
+
+use crate::{
+    error::ProxyError,
+    http_request::HttpRequest,
+    http_response::HttpResponse,
+    http_version::Version,
+    status_code::StatusCode,
+    timeout::with_deadline,
+    utils::{connect_any_async, write_to_stream_async},
+};
+
+// handle a CONNECT request (RFC 9110 section 9.3.6): open a tcp connection to the
+// requested host and port, confirm with 200 and copy bytes both ways until either side closes.
+// connecting may take up to connect_timeout
+pub async fn tunnel<S: AsyncRead + AsyncWrite + Unpin>(
+    client_socket: &mut S,
+    request: &HttpRequest,
+    allowed_ports: &[u16],
+    connect_timeout: Duration,
+) {
+    // ipv6 hosts come back in brackets from the url
+    let host = request
+        .url
+        .host_str()
+        .unwrap_or_default()
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    let port = request.url.port_or_known_default().unwrap_or(443);
+
+    if !allowed_ports.contains(&port) {
+        log::warn!("refusing CONNECT to {}:{}, port not allowed", host, port);
+        reply(client_socket, StatusCode::Forbidden, "Forbidden").await;
+        return;
+    }
+
+    let mut upstream = match connect(&host, port, connect_timeout).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            log::error!("failed to open tunnel to {}:{}: {:?}", host, port, e);
+            let status_code = e.status_code();
+            reply(client_socket, status_code, status_code.to_reason_phrase()).await;
+            return;
+        }
+    };
+
+    if let Err(e) = write_to_stream_async(
+        client_socket,
+        b"HTTP/1.1 200 Connection Established\r\n\r\n",
+    )
+    .await
+    {
+        log::error!("failed to write to socket: {:?}", e);
+        return;
+    }
+
+    match tokio::io::copy_bidirectional(client_socket, &mut upstream).await {
+        Ok((sent, received)) => {
+            log::debug!(
+                "tunnel to {}:{} closed, {} bytes sent, {} bytes received",
+                host,
+                port,
+                sent,
+                received
+            );
+        }
+        Err(e) => {
+            log::debug!("tunnel to {}:{} closed with error: {:?}", host, port, e);
+        }
+    }
+}
+
+async fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream, ProxyError> {
+    let what = format!("connecting to {}:{}", host, port);
+    let deadline = Instant::now() + timeout;
+    with_deadline(connect_any_async(host, port), timeout, deadline, &what).await
+}
+
+async fn reply<W: AsyncWrite + Unpin>(socket: &mut W, status_code: StatusCode, body: &str) {
+    let response = HttpResponse {
+        status_code,
+        reason: None,
+        version: Version::Http11,
+        headers: [("Content-Length".to_string(), body.len().to_string())]
+            .into_iter()
+            .collect(),
+        body: body.as_bytes().to_vec(),
+    };
+    if let Err(e) = write_to_stream_async(socket, &response.serialize()).await {
+        log::error!("failed to write to socket: {:?}", e);
+    }
+}
+
+#[tokio::test]
+async fn test_tunnel() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // upstream echoes whatever comes through the tunnel
+    let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = upstream.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut socket, _) = upstream.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let raw_request = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port);
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+
+    let (mut client, mut proxy_side) = tokio::io::duplex(1024);
+    tokio::spawn(async move {
+        tunnel(&mut proxy_side, &request, &[port], Duration::from_secs(10)).await
+    });
+
+    let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+    let mut buffer = vec![0; established.len()];
+    client.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(buffer, established);
+
+    client.write_all(b"ping").await.unwrap();
+    let mut buffer = [0; 4];
+    client.read_exact(&mut buffer).await.unwrap();
+    assert_eq!(&buffer, b"ping");
+}
+
+#[tokio::test]
+async fn test_tunnel_port_not_allowed() {
+    use tokio::io::AsyncReadExt;
+
+    let raw_request = "CONNECT 127.0.0.1:25 HTTP/1.1\r\n\r\n";
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+
+    let (mut client, mut proxy_side) = tokio::io::duplex(1024);
+    tunnel(&mut proxy_side, &request, &[443], Duration::from_secs(10)).await;
+    drop(proxy_side);
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 403 Forbidden"));
+}
+
+#[tokio::test]
+async fn test_tunnel_connect_timeout() {
+    use tokio::io::AsyncReadExt;
+
+    let (listener, _queued) = crate::utils::unresponsive_listener().await;
+    let port = listener.local_addr().unwrap().port();
+    let raw_request = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", port);
+    let request = HttpRequest::from_stream(&mut raw_request.as_bytes()).unwrap();
+
+    let (mut client, mut proxy_side) = tokio::io::duplex(1024);
+    tunnel(
+        &mut proxy_side,
+        &request,
+        &[port],
+        Duration::from_millis(100),
+    )
+    .await;
+    drop(proxy_side);
+
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 504 Gateway Timeout"));
+}
@@ -1,26 +1,88 @@
-use crate::http_method::Method;
+use crate::body::BodyReader;
+use crate::chunked::encode_chunked;
+use crate::error::ProxyError;
+use crate::header_map::HeaderMap;
+use crate::http_method::{is_token, Method};
 use crate::http_request::HttpRequest;
 use crate::http_response::HttpResponse;
+use crate::http_version::Version;
 use crate::status_code::StatusCode;
 use dns_lookup::lookup_host;
 
-use std::collections::HashMap;
-use std::error;
-use std::io::BufRead;
-use std::io::BufReader;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
 use std::io::Read;
 use std::io::Write;
 use std::net::IpAddr;
+use std::net::SocketAddr;
 use std::net::TcpStream;
+use std::pin::{pin, Pin};
 use std::str::FromStr;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf,
+};
+
+// every RandomState is seeded differently, which is random enough for jitter
+// and picking endpoints
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u8(0);
+    hasher.finish()
+}
 
 // nslookup command to resolve domain name to IP address
-pub fn nslookup(domain_name: String) -> Result<IpAddr, Box<dyn std::error::Error>> {
+pub fn nslookup(domain_name: String) -> Result<IpAddr, ProxyError> {
     // resolve domain name to IP address
     match lookup_host(domain_name.as_str()) {
-        Ok(ips) => Ok(ips[0]),
-        Err(e) => Err(e.into()),
+        Ok(ips) => ips
+            .first()
+            .copied()
+            .ok_or_else(|| ProxyError::Dns(format!("no addresses found for {}", domain_name))),
+        Err(e) => Err(ProxyError::Dns(format!("{}: {}", domain_name, e))),
+    }
+}
+
+// nslookup without blocking the runtime, resolves on tokio's blocking pool
+pub async fn nslookup_async(domain_name: &str) -> Result<IpAddr, ProxyError> {
+    let addresses = nslookup_all_async(domain_name).await?;
+    Ok(addresses[0])
+}
+
+// every address domain_name resolves to, in the order the resolver returned them.
+// never empty
+pub async fn nslookup_all_async(domain_name: &str) -> Result<Vec<IpAddr>, ProxyError> {
+    // the port is required by lookup_host but not used
+    let addresses: Vec<IpAddr> = tokio::net::lookup_host((domain_name, 0))
+        .await
+        .map_err(|e| ProxyError::Dns(format!("{}: {}", domain_name, e)))?
+        .map(|address| address.ip())
+        .collect();
+    if addresses.is_empty() {
+        return Err(ProxyError::Dns(format!(
+            "no addresses found for {}",
+            domain_name
+        )));
     }
+    Ok(addresses)
+}
+
+// opens a connection to host:port, trying every address the host resolves to in turn
+// until one accepts
+pub async fn connect_any_async(host: &str, port: u16) -> Result<tokio::net::TcpStream, ProxyError> {
+    let mut error = None;
+    for ip_address in nslookup_all_async(host).await? {
+        match tokio::net::TcpStream::connect(SocketAddr::new(ip_address, port)).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                log::debug!("failed to connect to {} for {}: {}", ip_address, host, e);
+                error = Some(e);
+            }
+        }
+    }
+    let error = error.map(|e| e.to_string()).unwrap_or_default();
+    Err(ProxyError::Connect(format!("{}:{}: {}", host, port, error)))
 }
 
 // test nslookup with localhost
@@ -31,12 +93,17 @@ fn test_nslookup() {
     assert!(ip_address.is_ok());
 }
 
-pub fn write_to_stream(stream: &mut TcpStream, message: &str) -> Result<(), Box<dyn error::Error>> {
-    match stream.write(message.as_bytes()) {
+#[tokio::test]
+async fn test_nslookup_async() {
+    assert!(nslookup_async("localhost").await.is_ok());
+}
+
+pub fn write_to_stream(stream: &mut TcpStream, message: &[u8]) -> Result<(), ProxyError> {
+    match stream.write(message) {
         Ok(num_bytes) => {
             if num_bytes != message.len() {
                 log::error!("failed to write all bytes to socket");
-                return Err("failed to write all bytes to socket".into());
+                return Err(ProxyError::Io(std::io::ErrorKind::WriteZero.into()));
             }
             Ok(())
         }
@@ -47,105 +114,448 @@ pub fn write_to_stream(stream: &mut TcpStream, message: &str) -> Result<(), Box<
     }
 }
 
-pub fn read_request(stream: &mut dyn Read) -> Result<HttpRequest, Box<dyn std::error::Error>> {
-    let mut buf_reader = BufReader::new(stream);
-    let mut first_line = String::new();
-    buf_reader.read_line(&mut first_line)?;
+pub async fn write_to_stream_async<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    message: &[u8],
+) -> Result<(), ProxyError> {
+    if let Err(e) = stream.write_all(message).await {
+        log::error!("failed to write to socket {:?}", e);
+        return Err(e.into());
+    }
+    stream.flush().await?;
+    Ok(())
+}
 
-    let words_first_line: Vec<&str> = first_line.split_whitespace().collect();
-    let method = Method::from_str(words_first_line[0])?;
-    let resource = words_first_line[1];
-    let _protocol = words_first_line[2];
+// adapts a blocking reader to AsyncRead so the blocking parsers can reuse the async ones.
+// reads block the calling thread and never return Pending
+struct BlockingReader<'a>(&'a mut dyn Read);
+
+impl AsyncRead for BlockingReader<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let num_bytes = self.0.read(buf.initialize_unfilled())?;
+        buf.advance(num_bytes);
+        Poll::Ready(Ok(()))
+    }
+}
+
+// drives a future that only waits on a BlockingReader to completion on the current thread
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::yield_now();
+    }
+}
+
+// whether the Connection header lists token, compared case-insensitively
+pub fn connection_has_token(headers: &HeaderMap, token: &str) -> bool {
+    headers.get_all("Connection").any(|value| {
+        value
+            .split(',')
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    })
+}
+
+// longest line read_line accepts, including the line break
+pub const MAX_LINE_LENGTH: usize = 8 * 1024;
+// header fields a message head may have
+pub const MAX_HEADERS: usize = 100;
+
+// read_line that reports a line which is not valid utf-8 as a parse error and
+// does not read more than MAX_LINE_LENGTH bytes looking for the end of a line
+pub async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    line: &mut String,
+) -> Result<usize, ProxyError> {
+    let num_bytes = tokio::io::AsyncReadExt::take(reader, MAX_LINE_LENGTH as u64)
+        .read_line(line)
+        .await
+        .map_err(|e| match e.kind() {
+            std::io::ErrorKind::InvalidData => ProxyError::Parse(format!("invalid line: {}", e)),
+            _ => ProxyError::from(e),
+        })?;
+    if num_bytes == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(ProxyError::HeadTooLarge(format!(
+            "line longer than {} bytes",
+            MAX_LINE_LENGTH
+        )));
+    }
+    Ok(num_bytes)
+}
 
-    let mut headers: HashMap<String, String> = HashMap::new();
+// reads header lines until the empty line that ends the head of a message or
+// the trailer section of a chunked body
+pub async fn read_headers<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+) -> Result<HeaderMap, ProxyError> {
+    let mut headers = HeaderMap::new();
+    let mut count = 0;
     loop {
         let mut line = String::new();
-        let num_bytes = buf_reader.read_line(&mut line)?;
-        if num_bytes == 0 {
-            break;
+        let num_bytes = read_line(reader, &mut line).await?;
+        if num_bytes == 0 || !line.ends_with('\n') {
+            return Err(ProxyError::Parse(
+                "connection closed before the end of the header section".to_string(),
+            ));
         }
         if line == "\r\n" {
             break;
         }
+        count += 1;
+        if count > MAX_HEADERS {
+            return Err(ProxyError::HeadTooLarge(format!(
+                "more than {} header fields",
+                MAX_HEADERS
+            )));
+        }
 
-        let components: Vec<&str> = line.splitn(2, ':').collect();
-        let key: String = components[0].to_string();
-        let value: String = components[1].trim().to_string();
-        headers.insert(key, value);
+        // a name with whitespace before the colon could be read as a different
+        // header by the next hop, so only tokens are names (RFC 9112 section 5.1)
+        let (key, value) = line
+            .split_once(':')
+            .filter(|(key, _)| is_token(key))
+            .ok_or_else(|| ProxyError::Parse(format!("invalid header field {:?}", line)))?;
+        headers.append(key, value.trim());
     }
+    Ok(headers)
+}
 
-    let url = match url::Url::parse(resource) {
-        Ok(url) => url,
-        Err(_e) => {
-            // if url is not valid, then it is a path
-            let host_header = headers.get("Host").expect("missing host header");
-            let host_url = url::Url::parse(format!("http://{}", host_header).as_str()).unwrap();
-            host_url.join(resource).unwrap()
+// how the end of a message body is found
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BodyFraming {
+    // no body follows the head
+    Empty,
+    ContentLength(usize),
+    Chunked,
+    // body runs until the sender closes the connection, responses only
+    CloseDelimited,
+}
+
+// the length every Content-Length field agrees on, None without the header.
+// "5, 5" and repeated fields with the same value are one length (RFC 9110 section 8.6),
+// values that differ make the message invalid
+fn content_length(headers: &HeaderMap) -> Result<Option<usize>, ProxyError> {
+    let mut length = None;
+    for value in headers
+        .get_all("Content-Length")
+        .flat_map(|value| value.split(','))
+    {
+        let value = value.trim();
+        // parse alone would take "+5"
+        let parsed = Some(value)
+            .filter(|value| value.bytes().all(|b| b.is_ascii_digit()))
+            .and_then(|value| value.parse::<usize>().ok())
+            .ok_or_else(|| ProxyError::Parse(format!("invalid content length {:?}", value)))?;
+        if length.is_some_and(|length| length != parsed) {
+            return Err(ProxyError::Parse(
+                "conflicting Content-Length values".to_string(),
+            ));
         }
-    };
+        length = Some(parsed);
+    }
+    Ok(length)
+}
+
+// whether the body is chunked, None without Transfer-Encoding. the codings of every
+// Transfer-Encoding field are one list (RFC 9112 section 6.1), only a chunked
+// final coding makes the body chunked and it may only be applied once
+fn is_chunked(headers: &HeaderMap) -> Result<Option<bool>, ProxyError> {
+    let codings: Vec<&str> = headers
+        .get_all("Transfer-Encoding")
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|coding| !coding.is_empty())
+        .collect();
+    if codings.is_empty() {
+        return Ok(None);
+    }
+    let chunked = |coding: &&str| coding.eq_ignore_ascii_case("chunked");
+    let (last, rest) = codings.split_last().unwrap_or((&"", &[]));
+    if chunked(last) && rest.iter().any(chunked) {
+        return Err(ProxyError::Parse(
+            "chunked applied more than once".to_string(),
+        ));
+    }
+    Ok(Some(chunked(last)))
+}
 
-    // ready body. read content length bytes
-    let mut body = String::new();
-    if let Some(content_length) = headers.get("Content-Length") {
-        let content_length = content_length.parse::<usize>().unwrap();
-        let mut buffer = vec![0; content_length];
-        buf_reader.read_exact(&mut buffer)?;
-        body = String::from_utf8_lossy(&buffer).to_string();
+// framing of a request body (RFC 9112 section 6.3). a request with both
+// Transfer-Encoding and Content-Length is refused, proxies that picked a different
+// one would see the next request start somewhere else
+pub fn body_framing(headers: &HeaderMap) -> Result<BodyFraming, ProxyError> {
+    match (is_chunked(headers)?, content_length(headers)?) {
+        (Some(_), Some(_)) => Err(ProxyError::Parse(
+            "both Transfer-Encoding and Content-Length".to_string(),
+        )),
+        (Some(true), None) => Ok(BodyFraming::Chunked),
+        (Some(false), None) => Err(ProxyError::Parse(format!(
+            "unsupported transfer encoding {:?}",
+            headers
+                .get_all("Transfer-Encoding")
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+        (None, Some(length)) => Ok(BodyFraming::ContentLength(length)),
+        (None, None) => Ok(BodyFraming::Empty),
     }
+}
+
+// framing of a response body, which also depends on the status and the request method
+// (RFC 9112 section 6.3). responses without Content-Length or chunked encoding are
+// delimited by the connection closing. like requests, responses with both
+// Transfer-Encoding and Content-Length are refused
+pub fn response_body_framing(
+    status_code: u32,
+    request_method: &Method,
+    headers: &HeaderMap,
+) -> Result<BodyFraming, ProxyError> {
+    if *request_method == Method::Head
+        || (100..200).contains(&status_code)
+        || status_code == 204
+        || status_code == 304
+    {
+        return Ok(BodyFraming::Empty);
+    }
+    if *request_method == Method::Connect && (200..300).contains(&status_code) {
+        return Ok(BodyFraming::Empty);
+    }
+    match (is_chunked(headers)?, content_length(headers)?) {
+        (Some(_), Some(_)) => Err(ProxyError::Parse(
+            "both Transfer-Encoding and Content-Length".to_string(),
+        )),
+        (Some(true), None) => Ok(BodyFraming::Chunked),
+        (Some(false), None) => Ok(BodyFraming::CloseDelimited),
+        (None, Some(length)) => Ok(BodyFraming::ContentLength(length)),
+        (None, None) => Ok(BodyFraming::CloseDelimited),
+    }
+}
+
+// read a whole body according to its framing. trailer fields of a chunked body are merged into headers
+async fn read_body<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    framing: BodyFraming,
+    headers: &mut HeaderMap,
+) -> Result<Vec<u8>, ProxyError> {
+    let mut body_reader = BodyReader::new(reader, framing);
+    let body = body_reader.read_to_end().await?;
+    for (key, value) in body_reader.trailers().iter() {
+        headers.insert_if_absent(key, value);
+    }
+    Ok(body)
+}
+
+// body as it goes on the wire, re-chunked when the headers announce chunked encoding
+pub fn serialize_body(headers: &HeaderMap, body: &[u8]) -> Vec<u8> {
+    match body_framing(headers) {
+        Ok(BodyFraming::Chunked) => encode_chunked(body),
+        _ => body.to_vec(),
+    }
+}
+
+// parse the authority-form target of a CONNECT request, e.g. "example.com:443" or "[::1]:8443"
+pub fn parse_authority(authority: &str) -> Result<(String, u16), ProxyError> {
+    let (host, port) = authority
+        .rsplit_once(':')
+        .ok_or_else(|| ProxyError::Parse(format!("authority {:?} is missing a port", authority)))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(ProxyError::Parse(format!(
+            "authority {:?} is missing a host",
+            authority
+        )));
+    }
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| ProxyError::Parse(format!("invalid port in authority {:?}", authority)))?;
+    Ok((host.to_string(), port))
+}
+
+pub fn parse_url(url: &str) -> Result<url::Url, ProxyError> {
+    url::Url::parse(url).map_err(|e| ProxyError::Parse(format!("invalid url {:?}: {}", url, e)))
+}
+
+// origin-form part of a request-target: the path and query of an absolute-form
+// target like "http://example.com/a?b", or the target itself. the fragment is dropped
+pub fn origin_form(target: &str) -> String {
+    let target = target.split('#').next().unwrap_or_default();
+    if target.starts_with('/') || target == "*" {
+        return target.to_string();
+    }
+    let after_scheme = match target.split_once("://") {
+        Some((_scheme, rest)) => rest,
+        None => return format!("/{}", target),
+    };
+    match after_scheme.find(['/', '?']) {
+        Some(start) if after_scheme[start..].starts_with('/') => after_scheme[start..].to_string(),
+        Some(start) => format!("/{}", &after_scheme[start..]),
+        None => "/".to_string(),
+    }
+}
+
+pub fn read_request(stream: &mut dyn Read) -> Result<HttpRequest, ProxyError> {
+    let mut buf_reader = BufReader::new(BlockingReader(stream));
+    block_on(read_request_async(&mut buf_reader))
+}
+
+pub async fn read_request_async<R: AsyncBufRead + Unpin>(
+    buf_reader: &mut R,
+) -> Result<HttpRequest, ProxyError> {
+    let (mut request, framing) = read_request_head_async(buf_reader).await?;
+    request.body = read_body(buf_reader, framing, &mut request.headers).await?;
+    Ok(request)
+}
+
+// read the request line and headers only. the body is left in buf_reader,
+// the returned framing tells how to read it
+pub async fn read_request_head_async<R: AsyncBufRead + Unpin>(
+    buf_reader: &mut R,
+) -> Result<(HttpRequest, BodyFraming), ProxyError> {
+    let mut first_line = String::new();
+    read_line(buf_reader, &mut first_line).await?;
+
+    let words_first_line: Vec<&str> = first_line.split_whitespace().collect();
+    let (method, resource, version) = match words_first_line[..] {
+        [method, resource, version] => (method, resource, version),
+        _ => {
+            return Err(ProxyError::Parse(format!(
+                "invalid request line {:?}",
+                first_line.trim_end()
+            )))
+        }
+    };
+    let method = Method::from_str(method)?;
+    let version = Version::from_str(version)?;
+
+    let headers = read_headers(buf_reader).await?;
+
+    let url = if method == Method::Connect {
+        // CONNECT carries the authority-form target, e.g. "example.com:443"
+        parse_authority(resource)?;
+        parse_url(&format!("http://{}", resource))?
+    } else {
+        match url::Url::parse(resource) {
+            Ok(url) => url,
+            Err(_e) => {
+                // if url is not valid, then it is a path
+                let host_header = headers.get("Host").ok_or_else(|| {
+                    ProxyError::Parse(format!("missing host header for {:?}", resource))
+                })?;
+                parse_url(&format!("http://{}", host_header))?
+                    .join(resource)
+                    .map_err(|e| {
+                        ProxyError::Parse(format!("invalid target {:?}: {}", resource, e))
+                    })?
+            }
+        }
+    };
+
+    let target = if method == Method::Connect {
+        None
+    } else {
+        Some(origin_form(resource))
+    };
+
+    let framing = body_framing(&headers)?;
 
     let request = HttpRequest {
-        body,
+        body: Vec::new(),
         headers,
         method,
         url,
+        target,
+        version,
     };
 
-    Ok(request)
+    Ok((request, framing))
+}
+
+pub fn read_response(stream: &mut dyn Read) -> Result<HttpResponse, ProxyError> {
+    let mut buf_reader = BufReader::new(BlockingReader(stream));
+    block_on(read_response_async(&mut buf_reader))
+}
+
+pub async fn read_response_async<R: AsyncBufRead + Unpin>(
+    buf_reader: &mut R,
+) -> Result<HttpResponse, ProxyError> {
+    read_response_for_async(buf_reader, &Method::Get).await
+}
+
+// read the response to a request sent with request_method, which decides whether a body follows
+pub async fn read_response_for_async<R: AsyncBufRead + Unpin>(
+    buf_reader: &mut R,
+    request_method: &Method,
+) -> Result<HttpResponse, ProxyError> {
+    let (mut response, framing) = read_response_head_async(buf_reader, request_method).await?;
+    response.body = read_body(buf_reader, framing, &mut response.headers).await?;
+    Ok(response)
 }
 
-pub fn read_response(stream: &mut dyn Read) -> Result<HttpResponse, Box<dyn std::error::Error>> {
+// read the status line and headers only. the body is left in buf_reader,
+// the returned framing tells how to read it
+pub async fn read_response_head_async<R: AsyncBufRead + Unpin>(
+    buf_reader: &mut R,
+    request_method: &Method,
+) -> Result<(HttpResponse, BodyFraming), ProxyError> {
     // input: "HTTP/1.1 200 OK\r\nDate: Mon, 23 May 2023 22:38:34 GMT\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: 132\r\n\r\n<html>\r\n<head>\r\n<title>An Example Page</title>\r\n</head>\r\n<body>\r\nHello World, this is a very simple HTML document.\r\n</body>\r\n</html>".to_string(),
-    let mut buf_reader = BufReader::new(stream);
     let mut first_line = String::new();
-    buf_reader.read_line(&mut first_line)?;
+    read_line(buf_reader, &mut first_line).await?;
 
-    let words_first_line: Vec<&str> = first_line.split_whitespace().collect();
-    let _protocol = words_first_line[0];
-    let status_code = StatusCode::from_u32(words_first_line[1].parse()?)?;
-
-    let mut headers: HashMap<String, String> = HashMap::new();
-    loop {
-        let mut line = String::new();
-        let num_bytes = buf_reader.read_line(&mut line)?;
-        if num_bytes == 0 {
-            break;
+    // status-line = HTTP-version SP status-code SP [ reason-phrase ]
+    let status_line = first_line.trim_end_matches(['\r', '\n']);
+    let mut parts = status_line.splitn(3, ' ');
+    let (version, status_code) = match (parts.next(), parts.next()) {
+        (Some(version), Some(status_code)) => (version, status_code),
+        _ => {
+            return Err(ProxyError::Parse(format!(
+                "invalid status line {:?}",
+                status_line
+            )))
         }
-        if line == "\r\n" {
-            break;
-        }
-
-        let components: Vec<&str> = line.splitn(2, ':').collect();
-        let key: String = components[0].to_string();
-        let value: String = components[1].trim().to_string();
-        headers.insert(key, value);
-    }
+    };
+    let reason = parts.next().unwrap_or_default().to_string();
+    let version = Version::from_str(version)?;
+    let status_code = status_code
+        .parse()
+        .map_err(|_| ProxyError::Parse(format!("invalid status code {:?}", status_code)))?;
+    let status_code = StatusCode::from_u32(status_code)?;
 
-    // ready body. read content length bytes
-    let mut body = String::new();
-    if let Some(content_length) = headers.get("Content-Length") {
-        let content_length = content_length.parse::<usize>().unwrap();
-        let mut buffer = vec![0; content_length];
-        buf_reader.read_exact(&mut buffer)?;
-        body = String::from_utf8_lossy(&buffer).to_string();
-    }
+    let headers = read_headers(buf_reader).await?;
+    let framing = response_body_framing(status_code.to_u32(), request_method, &headers)?;
 
     let response = HttpResponse {
-        body,
+        body: Vec::new(),
         headers,
         status_code,
+        reason: Some(reason),
+        version,
     };
 
-    Ok(response)
+    Ok((response, framing))
+}
+
+#[test]
+fn test_parse_authority() {
+    let tests = vec![
+        ("example.com:443", Some(("example.com", 443))),
+        ("127.0.0.1:8443", Some(("127.0.0.1", 8443))),
+        ("[::1]:443", Some(("::1", 443))),
+        ("example.com", None),
+        (":443", None),
+        ("example.com:https", None),
+    ];
+
+    for (input, expected) in tests {
+        let actual = parse_authority(input).ok();
+        let expected = expected.map(|(host, port)| (host.to_string(), port));
+        assert_eq!(actual, expected, "{}", input);
+    }
 }
 
 #[test]
@@ -182,12 +592,12 @@ fn test_read_request() {
         let request = read_request(&mut dummy_request).unwrap();
         assert_eq!(method, request.method.to_string());
         assert_eq!(path, request.url.path());
-        assert_eq!(host, request.headers["Host"]);
-        assert_eq!(user_agent, request.headers["User-Agent"]);
-        assert_eq!(accept, request.headers["Accept"]);
+        assert_eq!(host, &request.headers["Host"]);
+        assert_eq!(user_agent, &request.headers["User-Agent"]);
+        assert_eq!(accept, &request.headers["Accept"]);
         if request.method != Method::Get {
-            assert_eq!(content_length, request.headers["Content-Length"]);
-            assert_eq!(body, request.body);
+            assert_eq!(content_length, &request.headers["Content-Length"]);
+            assert_eq!(body.as_bytes(), request.body);
         }
     }
 }
@@ -196,26 +606,30 @@ fn test_read_request() {
 fn test_read_request_lifecycle() {
     let tests_requests: Vec<HttpRequest> = vec![
         HttpRequest {
-            method: http_method::Method::Get,
+            method: Method::Get,
             url: url::Url::parse("http://example.com").unwrap(),
-            headers: HashMap::from([
+            target: None,
+            version: Version::Http11,
+            headers: HeaderMap::from([
                 ("Content-Length".to_string(), "5".to_string()),
                 ("Host".to_string(), "example.com".to_string()),
                 ("User-Agent".to_string(), "curl".to_string()),
                 ("Accept".to_string(), "*/*".to_string()),
             ]),
-            body: "hello".to_string(),
+            body: "hello".into(),
         },
         HttpRequest {
-            method: http_method::Method::Post,
+            method: Method::Post,
             url: url::Url::parse("http://example.com").unwrap(),
-            headers: HashMap::from([
+            target: None,
+            version: Version::Http11,
+            headers: HeaderMap::from([
                 ("Content-Length".to_string(), "14".to_string()),
                 ("Host".to_string(), "example.com".to_string()),
                 ("User-Agent".to_string(), "curl".to_string()),
                 ("Accept".to_string(), "*/*".to_string()),
             ]),
-            body: "<h1>hello</h1>".to_string(),
+            body: "<h1>hello</h1>".into(),
         },
     ];
 
@@ -248,7 +662,7 @@ fn test_read_request_lifecycle() {
 
     for input in tests_requests {
         let serialized = input.serialize();
-        let mut dummy_request = serialized.as_bytes();
+        let mut dummy_request = serialized.as_slice();
         let request = read_request(&mut dummy_request).unwrap();
         assert_eq!(input.method, request.method);
         assert_eq!(input.url, request.url);
@@ -270,29 +684,33 @@ fn test_read_response_lifecycle() {
     let tests_responses: Vec<HttpResponse> = vec![
         HttpResponse {
             status_code: StatusCode::OK,
-            headers: HashMap::from([
+            reason: None,
+            version: Version::Http11,
+            headers: HeaderMap::from([
                 ("Content-Length".to_string(), "5".to_string()),
                 ("Host".to_string(), "example.com".to_string()),
                 ("User-Agent".to_string(), "curl".to_string()),
                 ("Accept".to_string(), "*/*".to_string()),
             ]),
-            body: "hello".to_string(),
+            body: "hello".into(),
         },
         HttpResponse {
             status_code: StatusCode::OK,
-            headers: HashMap::from([
+            reason: None,
+            version: Version::Http11,
+            headers: HeaderMap::from([
                 ("Content-Length".to_string(), "14".to_string()),
                 ("Host".to_string(), "example.com".to_string()),
                 ("User-Agent".to_string(), "curl".to_string()),
                 ("Accept".to_string(), "*/*".to_string()),
             ]),
-            body: "<h1>hello</h1>".to_string(),
+            body: "<h1>hello</h1>".into(),
         },
     ];
 
     for input in tests_responses {
         let serialized = input.serialize();
-        let mut dummy_response = serialized.as_bytes();
+        let mut dummy_response = serialized.as_slice();
         let response = read_response(&mut dummy_response).unwrap();
         assert_eq!(input.status_code, response.status_code);
         assert_eq!(input.headers["Host"], response.headers["Host"]);
@@ -300,3 +718,316 @@ fn test_read_response_lifecycle() {
         assert_eq!(input.headers["Accept"], response.headers["Accept"]);
     }
 }
+
+#[tokio::test]
+async fn test_read_request_async() {
+    let mut stream = BufReader::new(
+        "POST /users/1 HTTP/1.1\r\nHost: google.com\r\nContent-Length: 5\r\n\r\nhello".as_bytes(),
+    );
+    let request = read_request_async(&mut stream).await.unwrap();
+    assert_eq!(request.method, Method::Post);
+    assert_eq!(request.url.as_str(), "http://google.com/users/1");
+    assert_eq!(request.body, b"hello");
+}
+
+#[tokio::test]
+async fn test_read_response_async() {
+    let mut stream =
+        BufReader::new("HTTP/1.1 404 Not Found\r\nContent-Length: 4\r\n\r\nnope".as_bytes());
+    let response = read_response_async(&mut stream).await.unwrap();
+    assert_eq!(response.status_code, StatusCode::NotFound);
+    assert_eq!(response.body, b"nope");
+}
+
+#[test]
+fn test_body_framing() {
+    let tests = vec![
+        (vec![], Some(BodyFraming::Empty)),
+        (
+            vec![("Content-Length", "12")],
+            Some(BodyFraming::ContentLength(12)),
+        ),
+        (
+            vec![("content-length", "3")],
+            Some(BodyFraming::ContentLength(3)),
+        ),
+        (
+            vec![("Transfer-Encoding", "chunked")],
+            Some(BodyFraming::Chunked),
+        ),
+        (
+            vec![("Transfer-Encoding", "gzip, Chunked")],
+            Some(BodyFraming::Chunked),
+        ),
+        (
+            vec![
+                ("Transfer-Encoding", "gzip"),
+                ("Transfer-Encoding", "chunked"),
+            ],
+            Some(BodyFraming::Chunked),
+        ),
+        (vec![("Transfer-Encoding", "gzip")], None),
+        // every field counts, not only the first one
+        (
+            vec![
+                ("Transfer-Encoding", "chunked"),
+                ("Transfer-Encoding", "gzip"),
+            ],
+            None,
+        ),
+        (vec![("Transfer-Encoding", "chunked, chunked")], None),
+        (
+            vec![("Transfer-Encoding", "chunked"), ("Content-Length", "5")],
+            None,
+        ),
+        (
+            vec![("Content-Length", "5"), ("Content-Length", "5")],
+            Some(BodyFraming::ContentLength(5)),
+        ),
+        (
+            vec![("Content-Length", "5, 5")],
+            Some(BodyFraming::ContentLength(5)),
+        ),
+        (vec![("Content-Length", "5"), ("Content-Length", "6")], None),
+        (vec![("Content-Length", "5, 6")], None),
+        (vec![("Content-Length", "five")], None),
+        (vec![("Content-Length", "+5")], None),
+    ];
+
+    for (headers, expected) in tests {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(body_framing(&headers).ok(), expected, "{:?}", headers);
+    }
+}
+
+#[test]
+fn test_read_chunked_response_lifecycle() {
+    let input = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: X-Checksum\r\n\r\n5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: abc\r\n\r\n";
+    let response = read_response(&mut input.as_bytes()).unwrap();
+    assert_eq!(response.body, b"hello world");
+    assert_eq!(&response.headers["X-Checksum"], "abc");
+
+    // serializing re-chunks the body so the message stays valid for the next hop
+    let serialized = response.serialize();
+    assert!(serialized.ends_with(b"\r\n\r\nb\r\nhello world\r\n0\r\n\r\n"));
+    let reparsed = read_response(&mut serialized.as_slice()).unwrap();
+    assert_eq!(reparsed.body, b"hello world");
+}
+
+#[test]
+fn test_read_chunked_request_lifecycle() {
+    let input = "POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\nGET / HTTP/1.1\r\n";
+    let request = read_request(&mut input.as_bytes()).unwrap();
+    assert_eq!(request.body, b"Wikipedia");
+
+    let serialized = request.serialize();
+    let reparsed = read_request(&mut serialized.as_slice()).unwrap();
+    assert_eq!(reparsed.body, b"Wikipedia");
+}
+
+#[test]
+fn test_response_body_framing() {
+    let tests = vec![
+        (
+            200,
+            Method::Get,
+            vec![("Content-Length", "5")],
+            BodyFraming::ContentLength(5),
+        ),
+        (
+            200,
+            Method::Get,
+            vec![("Transfer-Encoding", "chunked")],
+            BodyFraming::Chunked,
+        ),
+        (
+            200,
+            Method::Get,
+            vec![("Transfer-Encoding", "gzip")],
+            BodyFraming::CloseDelimited,
+        ),
+        (200, Method::Get, vec![], BodyFraming::CloseDelimited),
+        (
+            200,
+            Method::Head,
+            vec![("Content-Length", "5")],
+            BodyFraming::Empty,
+        ),
+        (200, Method::Connect, vec![], BodyFraming::Empty),
+        (101, Method::Get, vec![], BodyFraming::Empty),
+        (
+            204,
+            Method::Get,
+            vec![("Content-Length", "5")],
+            BodyFraming::Empty,
+        ),
+        (
+            304,
+            Method::Get,
+            vec![("Transfer-Encoding", "chunked")],
+            BodyFraming::Empty,
+        ),
+        (404, Method::Post, vec![], BodyFraming::CloseDelimited),
+        (
+            200,
+            Method::Get,
+            vec![
+                ("Transfer-Encoding", "chunked"),
+                ("Transfer-Encoding", "gzip"),
+            ],
+            BodyFraming::CloseDelimited,
+        ),
+        (
+            200,
+            Method::Get,
+            vec![("Content-Length", "5"), ("Content-Length", "5")],
+            BodyFraming::ContentLength(5),
+        ),
+    ];
+
+    for (status_code, method, headers, expected) in tests {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        assert_eq!(
+            response_body_framing(status_code, &method, &headers).unwrap(),
+            expected,
+            "{} {} {:?}",
+            status_code,
+            method,
+            headers
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_read_close_delimited_response() {
+    let mut stream = BufReader::new(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nuntil the connection closes".as_bytes(),
+    );
+    let response = read_response_async(&mut stream).await.unwrap();
+    assert_eq!(response.body, b"until the connection closes");
+}
+
+#[tokio::test]
+async fn test_read_head_response() {
+    // the Content-Length describes the GET response, nothing follows the head
+    let (client, mut server) = tokio::io::duplex(1024);
+    server
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1000\r\n\r\n")
+        .await
+        .unwrap();
+    let mut stream = BufReader::new(client);
+    let response = read_response_for_async(&mut stream, &Method::Head)
+        .await
+        .unwrap();
+    assert_eq!(response.body, b"");
+    assert_eq!(&response.headers["Content-Length"], "1000");
+    drop(server);
+}
+
+#[tokio::test]
+async fn test_malformed_messages_are_parse_errors() {
+    let requests = vec![
+        "",
+        "GET\r\n\r\n",
+        "GET /\r\nHost: localhost\r\n\r\n",
+        "G@T / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+        "GET / HTTP/2.0\r\nHost: localhost\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost localhost\r\n\r\n",
+        "GET / HTTP/1.1\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\nContent-Length: ten\r\n\r\n",
+        "CONNECT example.com HTTP/1.1\r\n\r\n",
+        "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nContent-Length: 4\r\n\r\n0\r\n\r\n",
+        "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: identity\r\n\r\n",
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 4\r\nContent-Length: 40\r\n\r\nbody",
+        // field names are tokens, nothing may come between the name and the colon
+        "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length : 5\r\n\r\nhello",
+        "POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding\t: chunked\r\n\r\n0\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\n: empty\r\n\r\n",
+        "GET / HTTP/1.1\r\nHost: localhost\r\n X-Folded: value\r\n\r\n",
+        // cut off before the empty line that ends the head
+        "GET / HTTP/1.1\r\nHost: localhost\r\n",
+        "GET / HTTP/1.1\r\nHost: local",
+    ];
+    for input in requests {
+        let result = read_request_async(&mut input.as_bytes()).await;
+        assert!(matches!(result, Err(ProxyError::Parse(_))), "{:?}", input);
+    }
+
+    let responses = vec![
+        "HTTP/1.1\r\n\r\n",
+        "HTTP/1.1 abc OK\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length: -1\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+        "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello",
+        "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n",
+        "HTTP/1.1 200 OK\r\nContent-Length : 0\r\n\r\n",
+    ];
+    for input in responses {
+        let result = read_response_async(&mut input.as_bytes()).await;
+        assert!(matches!(result, Err(ProxyError::Parse(_))), "{:?}", input);
+    }
+
+    let invalid_utf8: &[u8] = b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n";
+    let result = read_request_async(&mut &invalid_utf8[..]).await;
+    assert!(matches!(result, Err(ProxyError::Parse(_))));
+}
+
+#[tokio::test]
+async fn test_head_limits() {
+    let long_value = "a".repeat(MAX_LINE_LENGTH);
+    let many_headers: String = (0..=MAX_HEADERS)
+        .map(|i| format!("X-Header-{}: {}\r\n", i, i))
+        .collect();
+    let tests = vec![
+        format!("GET /{} HTTP/1.1\r\nHost: localhost\r\n\r\n", long_value),
+        format!(
+            "GET / HTTP/1.1\r\nHost: localhost\r\nX-Long: {}\r\n\r\n",
+            long_value
+        ),
+        format!("GET / HTTP/1.1\r\nHost: localhost\r\n{}\r\n", many_headers),
+    ];
+    for input in tests {
+        let result = read_request_async(&mut input.as_bytes()).await;
+        assert!(
+            matches!(result, Err(ProxyError::HeadTooLarge(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    // right at the limits
+    let value = "a".repeat(MAX_LINE_LENGTH - "X-Long: \r\n".len());
+    let headers: String = (2..MAX_HEADERS)
+        .map(|i| format!("X-Header-{}: {}\r\n", i, i))
+        .collect();
+    let input = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\n{}X-Long: {}\r\n\r\n",
+        headers, value
+    );
+    let request = read_request_async(&mut input.as_bytes()).await.unwrap();
+    assert_eq!(request.headers.get("X-Long"), Some(value.as_str()));
+}
+
+// a listener that never accepts, with its backlog filled so connecting to it hangs
+#[cfg(test)]
+pub async fn unresponsive_listener() -> (tokio::net::TcpListener, Vec<tokio::net::TcpStream>) {
+    let socket = tokio::net::TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let address = listener.local_addr().unwrap();
+    let mut queued = Vec::new();
+    loop {
+        let connect = tokio::net::TcpStream::connect(address);
+        match tokio::time::timeout(std::time::Duration::from_millis(100), connect).await {
+            Ok(stream) => queued.push(stream.unwrap()),
+            Err(_) => return (listener, queued),
+        }
+    }
+}